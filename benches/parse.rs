@@ -0,0 +1,68 @@
+//! How long parsing takes and how much memory it needs, on a generated
+//! program of 100k lines. Run with `cargo bench`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// the system allocator, keeping track of how much is allocated
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+    PEAK.fetch_max(now, Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    System.dealloc(ptr, layout)
+  }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const LINES: usize = 100_000;
+
+/// ten lines of everything the parser has to deal with, numbered by `n`
+fn chunk(n: usize) -> String {
+  format!(r#"/// adds up to {n}
+fn add_{n}(a: i32, b: i32) -> i32 {{
+  let total = a * {n} + (b << 2) - 0x1f;
+  for i in 0..b {{
+    total += i; // running total
+  }}
+  if total < 100 {{ print_str("small\n"); }} else {{ total = total / 2; }}
+  return total;
+}}
+let result_{n}: i32 = add_{n}({n}, 3) as i32; print_dec(result_{n});
+"#)
+}
+
+fn main() {
+  let src: String = (0..LINES / 10).map(chunk).collect();
+  println!("{} lines, {} KB", src.lines().count(), src.len() / 1024);
+
+  let mut best = Duration::MAX;
+  let mut peak = 0;
+  for run in 0..5 {
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let start = Instant::now();
+    let (ast, errors) = compiler::parse::parse(&src);
+    best = best.min(start.elapsed());
+    // the memory is the first run's, since the later ones find every name
+    // already interned and would leave the interner out
+    if run == 0 {
+      peak = PEAK.load(Ordering::Relaxed) - before;
+    }
+    assert_eq!(errors, 0);
+    assert_eq!(ast.len(), LINES / 10 * 3);
+  }
+  println!("parse: {:.1} ms, {:.1} MB allocated at most", best.as_secs_f64() * 1000.0, peak as f64 / 1e6);
+}
@@ -1,94 +1,260 @@
+# the standard library. Each routine starts with a "# unit: name" line, and
+# "# needs: a b" lists other routines it calls. Only the routines a program
+# uses (and the ones they need) end up in the output.
+
+# unit: print_dec
 print_dec:
     li  a7, 1 # set rars ecall identifier to 1 for print_int
     # the value to print should already be in a0, so nothing else needs to be done.
     ecall
     ret
+# unit: print_str
 print_str:
     li  a7, 4 # set rars ecall identifier to 4 for print_string
     # the address to print should already be in a0, so nothing else needs to be done.
     ecall
     ret
+# unit: read_int
 read_int:
     li  a7, 5 # set rars ecall identifier to 5 for read int
     ecall     # ecall will place result in a0
     ret
+# unit: read_string
 read_string:
     li a7, 8 # set rars ecall identifier to 8 for read int
     ecall    # ecall assumes args already in a0 and a1
     ret
+# unit: sbrk
 sbrk: # im not going to comment all of these since theyre really all the same.
     li a7, 9
     ecall
     ret
+# unit: exit
 exit:
     li a7, 10
     ecall
     ret
+# unit: print_char
 print_char:
     li a7, 11
     ecall
     ret
+# unit: read_char
 read_char:
     li a7, 12
     ecall
     ret
+# unit: get_cwd
 get_cwd:
     li a7, 17
     ecall
     ret
+# unit: get_time
 get_time:
     li a7, 30
     ecall
     ret
+# unit: sleep
 sleep:
     li a7, 32
     ecall
     ret
+# unit: print_hex
 print_hex:
     li a7, 34
     ecall
     ret
+# unit: print_bin
 print_bin:
     li a7, 35
     ecall
     ret
+# unit: print_unsigned
 print_unsigned:
     li a7, 36
     ecall
     ret
+# unit: rand_seed
 rand_seed:
     li a7, 40
     ecall
     ret
+# unit: rand_int
 rand_int:
     li a7, 41
     ecall
     ret
+# unit: rand_int_range
 rand_int_range:
     li a7, 42
     ecall
     ret
+# unit: confirm_dialog
 confirm_dialog:
     li a7, 50
     ecall
     ret
+# unit: close
 close:
     li a7, 57
     ecall
     ret
+# unit: read_fd
 read_fd:
     li a7, 63
     ecall
     ret
+# unit: write_fd
 write_fd:
     li a7, 64
     ecall
     ret
+# unit: exit2
 exit2:
     li a7, 93
     ecall
     ret
+# unit: open
 open:
     li a7, 1024
     ecall
-    ret
\ No newline at end of file
+    ret
+# unit: __mulsi3
+# software multiply and divide, for targets without the M extension (--target rv32i).
+# these take their operands in a0 and a1 and only change a0.
+__mulsi3:
+    addi sp, sp, -12
+    sw a1, 0(sp)
+    sw a2, 4(sp)
+    sw a3, 8(sp)
+    mv a2, a0 # a2 is shifted left each step, a0 accumulates the product
+    li a0, 0
+__mulsi3_loop:
+    beqz a1, __mulsi3_done
+    andi a3, a1, 1
+    beqz a3, __mulsi3_skip
+    add a0, a0, a2
+__mulsi3_skip:
+    slli a2, a2, 1
+    srli a1, a1, 1
+    j __mulsi3_loop
+__mulsi3_done:
+    lw a1, 0(sp)
+    lw a2, 4(sp)
+    lw a3, 8(sp)
+    addi sp, sp, 12
+    ret
+# unit: __divsi3
+# needs: __divmodsi4
+__divsi3:
+    addi sp, sp, -8
+    sw ra, 0(sp)
+    sw a1, 4(sp)
+    call __divmodsi4
+    lw ra, 0(sp)
+    lw a1, 4(sp)
+    addi sp, sp, 8
+    ret
+# unit: __modsi3
+# needs: __divmodsi4
+__modsi3:
+    addi sp, sp, -8
+    sw ra, 0(sp)
+    sw a1, 4(sp)
+    call __divmodsi4
+    mv a0, a1
+    lw ra, 0(sp)
+    lw a1, 4(sp)
+    addi sp, sp, 8
+    ret
+# unit: __udivsi3
+# needs: __udivmodsi4
+__udivsi3:
+    addi sp, sp, -8
+    sw ra, 0(sp)
+    sw a1, 4(sp)
+    call __udivmodsi4
+    lw ra, 0(sp)
+    lw a1, 4(sp)
+    addi sp, sp, 8
+    ret
+# unit: __umodsi3
+# needs: __udivmodsi4
+__umodsi3:
+    addi sp, sp, -8
+    sw ra, 0(sp)
+    sw a1, 4(sp)
+    call __udivmodsi4
+    mv a0, a1
+    lw ra, 0(sp)
+    lw a1, 4(sp)
+    addi sp, sp, 8
+    ret
+# unit: __divmodsi4
+# needs: __udivmodsi4
+# signed a0 / a1, quotient in a0 and remainder in a1, rounding the same way div and rem do.
+__divmodsi4:
+    addi sp, sp, -16
+    sw ra, 0(sp)
+    sw a4, 4(sp)
+    sw a5, 8(sp)
+    bnez a1, __divmodsi4_nonzero
+    mv a1, a0 # x / 0 is -1 and x % 0 is x
+    li a0, -1
+    j __divmodsi4_done
+__divmodsi4_nonzero:
+    srai a4, a0, 31 # -1 if the dividend is negative
+    srai a5, a1, 31 # -1 if the divisor is negative
+    xor a0, a0, a4  # take absolute values
+    sub a0, a0, a4
+    xor a1, a1, a5
+    sub a1, a1, a5
+    xor a5, a5, a4  # -1 if the quotient is negative
+    call __udivmodsi4
+    xor a0, a0, a5  # the quotient is negative if the signs differ
+    sub a0, a0, a5
+    xor a1, a1, a4  # the remainder has the sign of the dividend
+    sub a1, a1, a4
+__divmodsi4_done:
+    lw ra, 0(sp)
+    lw a4, 4(sp)
+    lw a5, 8(sp)
+    addi sp, sp, 16
+    ret
+# unit: __udivmodsi4
+# unsigned a0 / a1, quotient in a0 and remainder in a1, the same as divu and remu.
+__udivmodsi4:
+    addi sp, sp, -16
+    sw a2, 0(sp)
+    sw a3, 4(sp)
+    sw a6, 8(sp)
+    sw a7, 12(sp)
+    bnez a1, __udivmodsi4_nonzero
+    mv a1, a0 # x / 0 is all ones and x % 0 is x
+    li a0, -1
+    j __udivmodsi4_done
+__udivmodsi4_nonzero:
+    li a2, 0        # quotient
+    li a3, 0        # remainder
+    li a6, 32       # bits left
+__udivmodsi4_loop:  # long division, one bit at a time
+    srli a7, a0, 31
+    slli a0, a0, 1
+    slli a3, a3, 1
+    or a3, a3, a7
+    slli a2, a2, 1
+    bltu a3, a1, __udivmodsi4_next
+    sub a3, a3, a1
+    ori a2, a2, 1
+__udivmodsi4_next:
+    addi a6, a6, -1
+    bnez a6, __udivmodsi4_loop
+    mv a0, a2
+    mv a1, a3
+__udivmodsi4_done:
+    lw a2, 0(sp)
+    lw a3, 4(sp)
+    lw a6, 8(sp)
+    lw a7, 12(sp)
+    addi sp, sp, 16
+    ret
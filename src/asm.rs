@@ -0,0 +1,231 @@
+use crate::riscv::{Reg, A0};
+
+/**
+ * A single line of lowered assembly. This is what the code generator emits
+ * and what the optimization passes in `opt.rs` work on, so it needs to know
+ * which registers each instruction reads and writes. Mnemonics are kept as
+ * plain strings since most passes don't care which operation it is.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Instr {
+  Label(String),
+  /// li rd, imm
+  Li(Reg, i64),
+  /// la rd, symbol
+  La(Reg, String),
+  /// loads like `lw rd, symbol`
+  LoadSym(&'static str, Reg, String),
+  /// stores like `sw rs, symbol, tmp` (tmp is clobbered with the address)
+  StoreSym(&'static str, Reg, String, Reg),
+  /// loads like `lw rd, offset(base)`
+  Load(&'static str, Reg, i32, Reg),
+  /// stores like `sw rs, offset(base)`
+  Store(&'static str, Reg, i32, Reg),
+  Mv(Reg, Reg),
+  /// three register operations like `add rd, rs1, rs2`
+  Op(&'static str, Reg, Reg, Reg),
+  /// register-immediate operations like `addi rd, rs1, imm`
+  OpImm(&'static str, Reg, Reg, i64),
+  /// two register pseudo-instructions like `seqz rd, rs`
+  Unary(&'static str, Reg, Reg),
+  /// compare-and-branch like `blt rs1, rs2, label`
+  Branch(&'static str, Reg, Reg, String),
+  /// compare-with-zero branch like `beqz rs, label`
+  BranchZ(&'static str, Reg, String),
+  Jump(String),
+  /// `jr rs`, where rs holds the address of one of these labels (taken from
+  /// a jump table)
+  JumpTable(Reg, Vec<String>),
+  /// call a routine, which may overwrite the given registers
+  Call(String, RegSet),
+  /// return from a user function, with the result in a0
+  Ret,
+  /// a system call, with its number already in a7
+  Ecall,
+}
+
+impl std::fmt::Display for Instr {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Instr::Label(l) => write!(f, "{}:", l),
+      Instr::Li(rd, imm) => write!(f, "li {}, {}", rd, imm),
+      Instr::La(rd, sym) => write!(f, "la {}, {}", rd, sym),
+      Instr::LoadSym(m, rd, sym) => write!(f, "{} {}, {}", m, rd, sym),
+      Instr::StoreSym(m, rs, sym, tmp) => write!(f, "{} {}, {}, {}", m, rs, sym, tmp),
+      Instr::Load(m, rd, off, base) => write!(f, "{} {}, {}({})", m, rd, off, base),
+      Instr::Store(m, rs, off, base) => write!(f, "{} {}, {}({})", m, rs, off, base),
+      Instr::Mv(rd, rs) => write!(f, "mv {}, {}", rd, rs),
+      Instr::Op(m, rd, rs1, rs2) => write!(f, "{} {}, {}, {}", m, rd, rs1, rs2),
+      Instr::OpImm(m, rd, rs1, imm) => write!(f, "{} {}, {}, {}", m, rd, rs1, imm),
+      Instr::Unary(m, rd, rs) => write!(f, "{} {}, {}", m, rd, rs),
+      Instr::Branch(m, rs1, rs2, l) => write!(f, "{} {}, {}, {}", m, rs1, rs2, l),
+      Instr::BranchZ(m, rs, l) => write!(f, "{} {}, {}", m, rs, l),
+      Instr::Jump(l) => write!(f, "j {}", l),
+      Instr::JumpTable(rs, _) => write!(f, "jr {}", rs),
+      Instr::Call(name, _) => write!(f, "call {}", name),
+      Instr::Ret => write!(f, "ret"),
+      Instr::Ecall => write!(f, "ecall"),
+    }
+  }
+}
+
+/// A set of registers, one bit per register.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RegSet(pub u32);
+
+impl RegSet {
+  pub fn with(self, r: Reg) -> Self {
+    RegSet(self.0 | 1 << r.0)
+  }
+
+  pub fn without(self, r: Reg) -> Self {
+    RegSet(self.0 & !(1 << r.0))
+  }
+
+  pub fn contains(self, r: Reg) -> bool {
+    self.0 & 1 << r.0 != 0
+  }
+
+  pub fn union(self, other: Self) -> Self {
+    RegSet(self.0 | other.0)
+  }
+
+  pub fn minus(self, other: Self) -> Self {
+    RegSet(self.0 & !other.0)
+  }
+
+  pub fn iter(self) -> impl Iterator<Item = Reg> {
+    (0..32u8).filter(move |i| self.0 & 1 << i != 0).map(Reg)
+  }
+}
+
+/// everything a called function is allowed to clobber: ra, t0-t6 and a0-a7.
+pub const CALLER_SAVED: RegSet = RegSet(0b1111_0000_0000_0011_1111_1100_1110_0010);
+/// s1-s11, which a called function has to put back the way they were. s0 is
+/// left out since it's the frame pointer.
+pub const CALLEE_SAVED: RegSet = RegSet(0b0000_1111_1111_1100_0000_0010_0000_0000);
+/// a0-a7, which is where call arguments are passed.
+pub const ARG_REGS: RegSet = RegSet(0b0000_0000_0000_0011_1111_1100_0000_0000);
+
+impl Instr {
+  /// registers written by this instruction
+  pub fn defs(&self) -> RegSet {
+    let set = RegSet::default();
+    match self {
+      Instr::Li(rd, _)
+      | Instr::La(rd, _)
+      | Instr::LoadSym(_, rd, _)
+      | Instr::Load(_, rd, _, _)
+      | Instr::Mv(rd, _)
+      | Instr::Op(_, rd, _, _)
+      | Instr::OpImm(_, rd, _, _)
+      | Instr::Unary(_, rd, _) => set.with(*rd),
+      Instr::StoreSym(_, _, _, tmp) => set.with(*tmp),
+      Instr::Call(_, clobbers) => *clobbers,
+      Instr::Ecall => ARG_REGS,
+      Instr::Label(_)
+      | Instr::Store(..)
+      | Instr::Branch(..)
+      | Instr::BranchZ(..)
+      | Instr::Jump(_)
+      | Instr::JumpTable(..)
+      | Instr::Ret => set,
+    }
+    .without(crate::riscv::ZERO)
+  }
+
+  /// registers read by this instruction
+  pub fn uses(&self) -> RegSet {
+    let set = RegSet::default();
+    match self {
+      Instr::StoreSym(_, rs, _, _) | Instr::Load(_, _, _, rs) => set.with(*rs),
+      Instr::Store(_, rs, _, base) => set.with(*rs).with(*base),
+      Instr::Mv(_, rs) | Instr::OpImm(_, _, rs, _) | Instr::Unary(_, _, rs) => set.with(*rs),
+      Instr::Op(_, _, rs1, rs2) | Instr::Branch(_, rs1, rs2, _) => set.with(*rs1).with(*rs2),
+      Instr::BranchZ(_, rs, _) | Instr::JumpTable(rs, _) => set.with(*rs),
+      Instr::Call(..) | Instr::Ecall => ARG_REGS,
+      Instr::Ret => set.with(A0),
+      Instr::Label(_)
+      | Instr::Li(..)
+      | Instr::La(..)
+      | Instr::LoadSym(..)
+      | Instr::Jump(_) => set,
+    }
+  }
+
+  /**
+   * Rewrites every register this instruction reads using `f`. Written
+   * registers are left alone.
+   */
+  pub fn map_uses(&mut self, mut f: impl FnMut(Reg) -> Reg) {
+    match self {
+      Instr::StoreSym(_, rs, _, tmp) => {
+        // the address goes into tmp before rs gets stored, so they can't be
+        // the same register. Keeping the old one is always fine.
+        let new = f(*rs);
+        if new != *tmp {
+          *rs = new;
+        }
+      },
+      Instr::Load(_, _, _, rs) => *rs = f(*rs),
+      Instr::Store(_, rs, _, base) => {
+        *rs = f(*rs);
+        *base = f(*base);
+      },
+      Instr::Mv(_, rs) | Instr::OpImm(_, _, rs, _) | Instr::Unary(_, _, rs) => *rs = f(*rs),
+      Instr::Op(_, _, rs1, rs2) | Instr::Branch(_, rs1, rs2, _) => {
+        *rs1 = f(*rs1);
+        *rs2 = f(*rs2);
+      },
+      Instr::BranchZ(_, rs, _) | Instr::JumpTable(rs, _) => *rs = f(*rs),
+      _ => (),
+    }
+  }
+
+  /**
+   * Rewrites the register written by this instruction. Only valid for
+   * instructions that write exactly one register.
+   */
+  pub fn set_def(&mut self, r: Reg) {
+    match self {
+      Instr::Li(rd, _)
+      | Instr::La(rd, _)
+      | Instr::LoadSym(_, rd, _)
+      | Instr::Load(_, rd, _, _)
+      | Instr::Mv(rd, _)
+      | Instr::Op(_, rd, _, _)
+      | Instr::OpImm(_, rd, _, _)
+      | Instr::Unary(_, rd, _) => *rd = r,
+      _ => panic!("set_def on an instruction without a single destination: {}", self),
+    }
+  }
+
+  /// true for instructions that only compute a value from their operands.
+  pub fn is_pure(&self) -> bool {
+    matches!(self, Instr::Li(..) | Instr::La(..) | Instr::Mv(..) | Instr::Op(..) | Instr::OpImm(..) | Instr::Unary(..))
+  }
+
+  /// the labels this instruction may transfer control to.
+  pub fn targets(&self) -> &[String] {
+    match self {
+      Instr::Branch(.., l) | Instr::BranchZ(.., l) | Instr::Jump(l) => std::slice::from_ref(l),
+      Instr::JumpTable(_, labels) => labels,
+      _ => &[],
+    }
+  }
+
+  /// true if execution can continue with the next instruction.
+  pub fn falls_through(&self) -> bool {
+    !matches!(self, Instr::Jump(_) | Instr::JumpTable(..) | Instr::Ret)
+  }
+
+  /// true for instructions that end a basic block.
+  pub fn ends_block(&self) -> bool {
+    !self.targets().is_empty() || *self == Instr::Ret
+  }
+}
+
+/// a0-a7 by index, for passing call arguments.
+pub fn arg_reg(i: usize) -> Reg {
+  Reg(A0.0 + i as u8)
+}
@@ -0,0 +1,841 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use crate::diag::{Source, Span, Spanned};
+use crate::expr::*;
+use crate::intern::Symbol;
+use crate::riscv::const_eval;
+use crate::stdlib::{self, Kind};
+
+/**
+ * Checks that every value is used as the type it is, between parsing and
+ * compiling. Variables without a type get the type of their initial value,
+ * and parameters and return values without one are `i32`.
+ *
+ * The code generator doesn't know about types, so this also rewrites
+ * whatever depends on them:
+ * - pointer arithmetic into plain byte arithmetic: for a `*i32` p, `p + n`
+ *   becomes `p + n * 4` and `p - q` becomes `(p - q) / 4`.
+ * - arithmetic on `u8`, `i8`, `u16` and `i16` gets an `as` around it, so
+ *   that it wraps around at the right size.
+ * - comparisons, `>>`, `/` and `%` on `u32` become their unsigned versions.
+ * - every `let`, `*p` and `*p = value` gets told how wide its value is.
+ * - every `struct` gets laid out, and every field access told where in it
+ *   the field is.
+ * - the names of `const`s and `enum` variants become their values, so that
+ *   they work like any other literal.
+ *
+ * Returns how many errors there were.
+ */
+pub fn check(program: &mut Block, src: &Source) -> usize {
+  let mut c = Checker {
+    src, errors: 0, fns: HashMap::new(), structs: HashMap::new(), consts: HashMap::new(),
+    scopes: vec![HashMap::new()], loops: vec![], ret: None, span: Span::default(),
+  };
+  let mut decls = HashMap::new();
+  for s in program.iter_mut() {
+    match &mut s.node {
+      Stmt::Fn(f) => {
+        c.span = s.span;
+        if stdlib::signature(f.name.as_str()).is_some() {
+          c.error(format!("{} is already defined by the standard library", f.name));
+        } else if f.params.len() > 8 {
+          c.error(format!("{} has more than 8 parameters", f.name));
+        } else if f.name == "main" && !f.params.is_empty() {
+          c.error("main can't take any parameters");
+        }
+        match c.fns.entry(f.name) {
+          Entry::Occupied(_) => c.error(format!("function {} is defined more than once", f.name)),
+          Entry::Vacant(e) => {
+            e.insert((f.param_types.clone(), f.ret.clone()));
+          },
+        }
+      },
+      Stmt::Const(name, value) => {
+        c.span = s.span;
+        let v = c.fold(name, value);
+        c.constant(name, v);
+      },
+      Stmt::Enum(_, variants) => {
+        c.span = s.span;
+        let mut next = 0;
+        for (name, value) in variants {
+          let v = match value {
+            Some(value) => c.fold(name, value),
+            None => next,
+          };
+          *value = Some(Expr::Lit(v));
+          c.constant(name, v);
+          next = v + 1;
+        }
+      },
+      Stmt::Struct(d) => {
+        match decls.entry(d.name) {
+          Entry::Occupied(_) => {
+            c.span = s.span;
+            c.error(format!("struct {} is defined more than once", d.name));
+          },
+          Entry::Vacant(e) => {
+            e.insert(d.fields.clone());
+          },
+        }
+      },
+      _ => (),
+    }
+  }
+
+  // a struct can contain ones defined after it, so they all get laid out
+  // before any of their types are checked
+  for s in program.iter() {
+    if let Stmt::Struct(d) = &s.node {
+      c.span = s.span;
+      c.layout(&d.name, &decls, &mut vec![]);
+    }
+  }
+  for s in program.iter_mut() {
+    if let Stmt::Struct(d) = &mut s.node {
+      c.span = s.span;
+      for (_, t) in &d.fields {
+        c.check_type(t);
+      }
+      let layout = &c.structs[&d.name];
+      (d.size, d.align) = (layout.size, layout.align);
+    }
+  }
+
+  // functions can use any global at the top level, including ones declared
+  // after them, so they're checked last.
+  for s in program.iter_mut() {
+    if !matches!(s.node, Stmt::Fn(_)) {
+      c.stmt(s);
+    }
+  }
+  for s in program.iter_mut() {
+    if let Stmt::Fn(f) = &mut s.node {
+      c.span = s.span;
+      for (p, t) in f.params.iter().zip(&f.param_types) {
+        if let Type::Struct(name) = t {
+          c.error(format!("{} can't be a {}, since structs can't be copied. Pass a pointer to it instead.", p, name));
+        }
+        c.check_type(t);
+      }
+      if let Type::Struct(name) = &f.ret {
+        c.error(format!("{} can't return a {}, since structs can't be copied", f.name, name));
+      }
+      c.check_type(&f.ret);
+      for (i, p) in f.params.iter().enumerate() {
+        if f.params[..i].contains(p) {
+          c.error(format!("{} has more than one parameter called {}", f.name, p));
+        } else if c.consts.contains_key(p) {
+          c.error(format!("{} is a constant, so it can't be a parameter", p));
+        }
+      }
+      c.ret = Some(f.ret.clone());
+      c.scopes.push(f.params.iter().cloned().zip(f.param_types.iter().cloned()).collect());
+      c.block(&mut f.body);
+      c.scopes.pop();
+    }
+  }
+  c.errors
+}
+
+struct Checker<'a> {
+  src: &'a Source<'a>,
+  errors: usize,
+  /// parameter and return types of every user function
+  fns: HashMap<Symbol, (Vec<Type>, Type)>,
+  structs: HashMap<Symbol, Layout>,
+  /// the value of every `const` and `enum` variant
+  consts: HashMap<Symbol, i64>,
+  /// the type of each variable, for each block we're in (innermost last)
+  scopes: Vec<HashMap<Symbol, Type>>,
+  /// the label of each loop we're in (innermost last), for `break` and
+  /// `continue`
+  loops: Vec<Option<Symbol>>,
+  /// what the function being checked returns, or None at the top level
+  ret: Option<Type>,
+  /// the statement being checked, which errors point at
+  span: Span,
+}
+
+/// where the fields of a struct are
+struct Layout {
+  /// name, type and offset of each field, in order
+  fields: Vec<(Symbol, Type, i64)>,
+  size: i64,
+  align: i64,
+}
+
+/// any of the integer types, or a `bool` that gets used as one
+fn is_int(t: &Type) -> bool {
+  t.range().is_some() || *t == Type::Unknown
+}
+
+/// `u8`, `i8`, `u16` or `i16`, which need cutting down after arithmetic
+fn is_narrow(t: &Type) -> bool {
+  t.width() != Width::Word
+}
+
+/// true if every value of `from` is also a value of `to`
+fn widens(from: &Type, to: &Type) -> bool {
+  match (from.range(), to.range()) {
+    (Some((a, b)), Some((c, d))) => c <= a && b <= d,
+    _ => false,
+  }
+}
+
+/// the value of `e` if it's a constant. Literals keep all their bits, so a
+/// `u32` can be bigger than what `const_eval` gives.
+fn constant(e: &Expr) -> Option<i64> {
+  match e {
+    Expr::Lit(v) => Some(*v),
+    e => const_eval(e),
+  }
+}
+
+/// a constant written with only numbers and operators, like `-1` or `1 << 4`,
+/// which can be whichever integer type it's used as
+fn is_literal(e: &Expr) -> bool {
+  match e {
+    Expr::Lit(_) => true,
+    Expr::Unary(UnaryOp::Neg | UnaryOp::Not, e) => is_literal(e),
+    Expr::Bin(l, _, r) => is_literal(l) && is_literal(r),
+    _ => false,
+  }
+}
+
+/// whether the constant `v` can be a `to`. Any 32 bit pattern works for the
+/// 32 bit types, so `-1` is a `u32` with every bit set.
+fn holds(to: &Type, v: i64) -> bool {
+  match to {
+    Type::I32 | Type::U32 => (i32::MIN as i64..=u32::MAX as i64).contains(&v),
+    _ => to.range().is_some_and(|(low, high)| (low..=high).contains(&v)),
+  }
+}
+
+/// replaces `e` with `f(e)`
+fn wrap(e: &mut Expr, f: impl FnOnce(Expr) -> Expr) {
+  let inner = std::mem::replace(e, Expr::Lit(0));
+  *e = f(inner);
+}
+
+/// puts an `as t` around `e` if it could have gone out of range for `t`
+fn narrow(e: &mut Expr, t: &Type) {
+  if is_narrow(t) {
+    wrap(e, |e| Expr::Cast(Box::new(e), t.clone()));
+  }
+}
+
+fn is_ptr(t: &Type) -> bool {
+  matches!(t, Type::Ptr(_) | Type::Str)
+}
+
+/// anything an `if` or a `while` can test
+fn is_truthy(t: &Type) -> bool {
+  !matches!(t, Type::Void | Type::Struct(_))
+}
+
+/// `n * size`, folded if `n` is a constant
+fn scale(n: Expr, size: i64) -> Expr {
+  if size == 1 {
+    return n;
+  }
+  match const_eval(&n) {
+    Some(v) => Expr::Lit((v as i32).wrapping_mul(size as i32) as i64),
+    None => Expr::Bin(Box::new(n), BinOp::Mul, Box::new(Expr::Lit(size))),
+  }
+}
+
+/// what a standard function returning `kind` gives
+fn kind_type(kind: Kind) -> Type {
+  match kind {
+    Kind::Void => Type::Void,
+    Kind::Int | Kind::Char | Kind::Fd => Type::I32,
+    Kind::Str => Type::Str,
+    Kind::Addr => Type::ptr(Type::I32),
+  }
+}
+
+impl<'a> Checker<'a> {
+  fn error(&mut self, message: impl std::fmt::Display) {
+    self.src.error(self.span, message);
+    self.errors += 1;
+  }
+
+  /// a `const` or `enum` variant called `name`, which can only be defined once
+  fn constant(&mut self, name: &Symbol, v: i64) {
+    match self.consts.entry(*name) {
+      Entry::Occupied(_) => self.error(format!("constant {} is defined more than once", name)),
+      Entry::Vacant(e) => {
+        e.insert(v);
+      },
+    }
+  }
+
+  fn var(&self, name: &Symbol) -> Option<&Type> {
+    self.scopes.iter().rev().find_map(|s| s.get(name))
+  }
+
+  /// the value of constant `name`, which `value` gets replaced with. It can
+  /// use the constants before it.
+  fn fold(&mut self, name: &Symbol, value: &mut Expr) -> i64 {
+    let t = self.expr(value);
+    match constant(value).filter(|_| is_int(&t)) {
+      Some(v) => {
+        *value = Expr::Lit(v);
+        v
+      },
+      None => {
+        self.error(format!("the value of {} has to be a number that's known without running anything", name));
+        0
+      },
+    }
+  }
+
+  /// reports it if `t` names a struct that doesn't exist, or points to one
+  fn check_type(&mut self, t: &Type) -> bool {
+    match t {
+      Type::Ptr(to) => self.check_type(to),
+      Type::Struct(name) if !self.structs.contains_key(name) => {
+        self.error(format!("unknown type {}", name));
+        false
+      },
+      _ => true,
+    }
+  }
+
+  /// how many bytes a `t` takes up in memory
+  fn size_of(&self, t: &Type) -> i64 {
+    match t {
+      Type::Void => 0,
+      Type::Struct(name) => self.structs.get(name).map_or(0, |l| l.size),
+      _ => t.width().bytes(),
+    }
+  }
+
+  /// the size of what `t` points at, if it's a pointer
+  fn pointee_size(&self, t: &Type) -> Option<i64> {
+    match t {
+      Type::Ptr(to) => Some(self.size_of(to)),
+      Type::Str => Some(1),
+      _ => None,
+    }
+  }
+
+  /**
+   * Works out where the fields of struct `name` go, and those of any struct
+   * it contains first. Each field starts at a multiple of its own size (or
+   * its struct's alignment), and the whole thing is padded to a multiple of
+   * its biggest alignment, so that they still line up in an array of them.
+   * `outer` are the structs `name` is inside of, which it can't contain.
+   */
+  fn layout(&mut self, name: &Symbol, decls: &HashMap<Symbol, Vec<(Symbol, Type)>>, outer: &mut Vec<Symbol>) -> Option<(i64, i64)> {
+    if let Some(l) = self.structs.get(name) {
+      return Some((l.size, l.align));
+    }
+    // a struct that doesn't exist is reported by check_type
+    let fields = decls.get(name)?;
+    if outer.iter().any(|o| o == name) {
+      self.error(format!("{} contains itself, so it would never end. Use a pointer to it instead.", name));
+      return None;
+    }
+    outer.push(*name);
+    let (mut offset, mut align) = (0, 1);
+    let mut placed: Vec<(Symbol, Type, i64)> = vec![];
+    for (field, t) in fields {
+      if placed.iter().any(|(f, _, _)| f == field) {
+        self.error(format!("{} has more than one field called {}", name, field));
+      }
+      let (size, a) = match t {
+        Type::Struct(inner) => self.layout(inner, decls, outer).unwrap_or((0, 1)),
+        _ => (t.width().bytes(), t.width().bytes()),
+      };
+      offset = (offset + a - 1) / a * a;
+      placed.push((*field, t.clone(), offset));
+      offset += size;
+      align = align.max(a);
+    }
+    outer.pop();
+    let size = (offset + align - 1) / align * align;
+    self.structs.insert(*name, Layout { fields: placed, size, align });
+    Some((size, align))
+  }
+
+  /// the type of field `m` of a `t`, filling in where it is
+  fn field(&mut self, t: &Type, m: &mut Member) -> Type {
+    let name = match t {
+      Type::Struct(name) => name,
+      Type::Unknown => return Type::Unknown,
+      Type::Ptr(to) if matches!(**to, Type::Struct(_)) => {
+        self.error(format!("{} is a pointer, use -> to get to its fields", t));
+        return Type::Unknown;
+      },
+      _ => {
+        self.error(format!("{} doesn't have fields", t));
+        return Type::Unknown;
+      },
+    };
+    // a struct that doesn't exist was already reported
+    let layout = match self.structs.get(name) {
+      Some(l) => l,
+      None => return Type::Unknown,
+    };
+    match layout.fields.iter().find(|(f, _, _)| *f == m.name) {
+      Some((_, ft, offset)) => {
+        m.offset = *offset as i32;
+        m.width = ft.width();
+        ft.clone()
+      },
+      None => {
+        self.error(format!("{} doesn't have a field called {}", name, m.name));
+        Type::Unknown
+      },
+    }
+  }
+
+  /**
+   * Whether a `from` can go where a `to` is wanted. Integers can go to any
+   * integer type that holds all of their values (a `bool` counts as one, but
+   * not the other way around), literals to any that holds them, and a
+   * literal 0 to any pointer.
+   */
+  fn fits(value: &Expr, from: &Type, to: &Type) -> bool {
+    from == to
+      || *from == Type::Unknown
+      || *to == Type::Unknown
+      || (*to != Type::Bool && widens(from, to))
+      || (*to != Type::Bool && is_int(from) && is_literal(value) && constant(value).is_some_and(|v| holds(to, v)))
+      || (is_ptr(to) && matches!(value, Expr::Lit(0)))
+  }
+
+  /// reports `mismatch` if `value` doesn't fit, or that it's out of range if
+  /// it's a literal
+  fn convert(&mut self, value: &Expr, from: &Type, to: &Type, mismatch: impl FnOnce() -> String) {
+    if *from != Type::Unknown && *to != Type::Unknown {
+      if let Type::Struct(name) = [from, to].into_iter().find(|t| matches!(t, Type::Struct(_))).unwrap_or(&Type::Void) {
+        self.error(format!("can't copy a {}, its fields have to be set one at a time", name));
+        return;
+      }
+    }
+    if Self::fits(value, from, to) {
+      return;
+    }
+    match constant(value).filter(|_| is_literal(value)) {
+      Some(v) if is_int(from) && to.range().is_some() && *to != Type::Bool => {
+        self.error(format!("{} doesn't fit in {}", v, to));
+      },
+      _ => self.error(mismatch()),
+    }
+  }
+
+  /**
+   * The type arithmetic on a `lt` and a `rt` is done in: whichever of them
+   * holds every value of the other, or `i32` if neither does. A literal
+   * becomes the type of the other side if it fits.
+   */
+  fn common(l: &Expr, lt: &Type, r: &Expr, rt: &Type) -> Option<Type> {
+    let int = |t: &Type| if *t == Type::Bool { Type::I32 } else { t.clone() };
+    let (a, b) = (int(lt), int(rt));
+    let adapts = |e: &Expr, t: &Type, to: &Type| is_literal(e) && Self::fits(e, t, to);
+    if adapts(l, lt, &b) {
+      return Some(b);
+    }
+    if adapts(r, rt, &a) {
+      return Some(a);
+    }
+    [a.clone(), b.clone(), Type::I32].into_iter().find(|t| widens(&a, t) && widens(&b, t))
+  }
+
+  fn block(&mut self, b: &mut Block) {
+    self.scopes.push(HashMap::new());
+    b.iter_mut().for_each(|s| self.stmt(s));
+    self.scopes.pop();
+  }
+
+  fn stmt(&mut self, s: &mut Spanned<Stmt>) {
+    let span = s.span;
+    self.span = span;
+    match &mut s.node {
+      Stmt::ExprStmt(e) => {
+        self.expr(e);
+      },
+      Stmt::Decl(name, ty, init) => {
+        let t = self.expr(init);
+        let t = match ty {
+          Some(ty) if !self.check_type(ty) => Type::Unknown,
+          // a struct starts out as all zeros, and can't be given a value
+          Some(Type::Struct(_)) if matches!(init, Expr::Lit(0)) => ty.clone().unwrap(),
+          Some(ty) => {
+            self.convert(init, &t, ty, || format!("{} is declared as {}, but its value is {}", name, ty, t));
+            ty.clone()
+          },
+          None if t == Type::Void => {
+            self.error(format!("the value of {} doesn't have a type, since it doesn't return anything", name));
+            Type::Unknown
+          },
+          None if matches!(t, Type::Struct(_)) => {
+            self.error(format!("can't copy a {}, its fields have to be set one at a time", t));
+            Type::Unknown
+          },
+          None => t,
+        };
+        *ty = Some(t.clone());
+        if self.consts.contains_key(name) {
+          self.error(format!("{} is a constant, so it can't be declared as a variable", name));
+        }
+        // a local lives in the function's frame, which can't be that big. At
+        // the top level it's a global, however big it is.
+        if self.ret.is_some() && matches!(t, Type::Struct(_)) && self.size_of(&t) > 1024 {
+          self.error(format!("{} is too big to be a local variable ({} bytes), make it a global instead", name, self.size_of(&t)));
+        }
+        // reported here instead of by the code generator, since once
+        // inlining renames the second one it wouldn't be
+        if self.scopes.last_mut().unwrap().insert(*name, t).is_some() {
+          self.error(format!("{} is already declared in this block", name));
+        }
+      },
+      Stmt::Assignment(name, value) => {
+        let t = self.expr(value);
+        if let Some(ty) = self.var(name).cloned() {
+          self.convert(value, &t, &ty, || format!("{} is {}, so it can't be set to {}", name, ty, t));
+        } else if self.consts.contains_key(name) {
+          self.error(format!("{} is a constant, so it can't be changed", name));
+        } else {
+          self.error(format!("unknown variable {}", name));
+        }
+      },
+      Stmt::Store(addr, value, width) => {
+        let to = self.expr(addr);
+        let t = self.expr(value);
+        let ty = match &to {
+          Type::Ptr(ty) => (**ty).clone(),
+          Type::Str => Type::U8,
+          Type::Unknown => return,
+          _ => {
+            self.error(format!("can't store through {}, it isn't a pointer", to));
+            return;
+          },
+        };
+        *width = ty.width();
+        self.convert(value, &t, &ty, || format!("can't store {} through {}", t, to));
+      },
+      Stmt::SetField(target, value) => {
+        let ty = self.expr(target);
+        let t = self.expr(value);
+        let name = match target {
+          Expr::Field(_, m) => m.name,
+          _ => unreachable!(),
+        };
+        self.convert(value, &t, &ty, || format!("{} is {}, so it can't be set to {}", name, ty, t));
+      },
+      Stmt::If(cond, t, f) => {
+        self.cond(cond, "if");
+        self.block(t);
+        if let Some(f) = f {
+          self.block(f);
+        }
+      },
+      Stmt::Loop(l) => {
+        self.scopes.push(HashMap::new());
+        self.loops.push(l.label);
+        l.init.iter_mut().for_each(|s| self.stmt(s));
+        self.span = span;
+        if let Some(cond) = &mut l.cond {
+          self.cond(cond, "loop");
+        }
+        self.block(&mut l.body);
+        self.block(&mut l.step);
+        self.loops.pop();
+        self.scopes.pop();
+      },
+      Stmt::Match(value, arms) => {
+        let t = self.expr(value);
+        if !is_int(&t) {
+          self.error(format!("can only match on numbers, not {}", t));
+        }
+        for p in arms.iter_mut().flat_map(|a| &mut a.patterns) {
+          if let Pattern::Const(name) = p {
+            match self.consts.get(name) {
+              Some(&v) => *p = Pattern::Value(v),
+              None => self.error(format!("{} isn't a constant, so it can't be a match pattern", name)),
+            }
+          }
+        }
+        arms.iter_mut().for_each(|a| self.block(&mut a.body));
+      },
+      Stmt::Return(value) => {
+        if self.ret.is_none() {
+          self.error("return outside of a function");
+        }
+        let t = value.as_mut().map(|e| self.expr(e));
+        if let (Some(ret), Some(t), Some(e)) = (self.ret.clone(), t, value) {
+          self.convert(e, &t, &ret, || format!("this function returns {}, not {}", ret, t));
+        }
+      },
+      Stmt::Break(label) => self.jump("break", label),
+      Stmt::Continue(label) => self.jump("continue", label),
+      // the ones at the top level were handled before anything else. Functions
+      // never get here from there.
+      Stmt::Fn(f) => self.error(format!("function {} has to be defined at the top level", f.name)),
+      Stmt::Struct(d) if self.scopes.len() > 1 => self.error(format!("struct {} has to be defined at the top level", d.name)),
+      Stmt::Const(name, _) if self.scopes.len() > 1 => self.error(format!("const {} has to be defined at the top level", name)),
+      Stmt::Enum(name, _) if self.scopes.len() > 1 => self.error(format!("enum {} has to be defined at the top level", name)),
+      Stmt::Struct(_) | Stmt::Const(..) | Stmt::Enum(..) | Stmt::Error => (),
+    }
+  }
+
+  /// a `break` or `continue` needs a loop to go to, with `label` if it has one
+  fn jump(&mut self, what: &str, label: &Option<Symbol>) {
+    match label {
+      Some(name) if !self.loops.contains(label) => {
+        self.error(format!("{} to '{}, but there's no loop with that name around it", what, name));
+      },
+      None if self.loops.is_empty() => self.error(format!("{} outside of a loop", what)),
+      _ => (),
+    }
+  }
+
+  fn cond(&mut self, e: &mut Expr, what: &str) {
+    let t = self.expr(e);
+    if !is_truthy(&t) {
+      self.error(format!("the condition of this {} doesn't have a value", what));
+    }
+  }
+
+  fn expr(&mut self, e: &mut Expr) -> Type {
+    match e {
+      Expr::Lit(_) => Type::I32,
+      Expr::String(_) => Type::Str,
+      Expr::Ident(name) => match (self.var(name), self.consts.get(name)) {
+        (Some(t), _) => t.clone(),
+        (None, Some(&v)) => {
+          *e = Expr::Lit(v);
+          Type::I32
+        },
+        (None, None) => {
+          self.error(format!("unknown variable {}", name));
+          Type::Unknown
+        },
+      },
+      Expr::Bin(..) => self.bin(e),
+      Expr::Call(name, args) => {
+        let types: Vec<Type> = args.iter_mut().map(|a| self.expr(a)).collect();
+        let (params, ret) = match (self.fns.get(name), stdlib::signature(name.as_str())) {
+          (Some((params, ret)), _) => (params.clone(), ret.clone()),
+          (None, Some(sig)) => {
+            // an address parameter takes any kind of pointer, and a number
+            // any kind of integer
+            let params = sig.params.iter().zip(&types).map(|((_, k), t)| match k {
+              Kind::Addr if is_ptr(t) => t.clone(),
+              Kind::Str => Type::Str,
+              Kind::Addr => Type::ptr(Type::I32),
+              _ if is_int(t) => t.clone(),
+              _ => Type::I32,
+            }).collect();
+            (params, kind_type(sig.ret))
+          },
+          (None, None) => {
+            let known = self.fns.keys().map(|k| k.as_str()).chain(stdlib::SIGNATURES.iter().map(|s| s.name));
+            match stdlib::did_you_mean(name.as_str(), known) {
+              Some(close) => self.error(format!("unknown function {} (did you mean {}?)", name, close)),
+              None => self.error(format!("unknown function {}", name)),
+            }
+            return Type::Unknown;
+          },
+        };
+        if params.len() != args.len() {
+          let n = params.len();
+          self.error(format!("{} takes {} argument{} but was given {}", name, n, if n == 1 { "" } else { "s" }, args.len()));
+          return ret;
+        }
+        for (i, ((arg, t), p)) in args.iter().zip(&types).zip(&params).enumerate() {
+          self.convert(arg, t, p, || format!("argument {} of {} should be {}, not {}", i + 1, name, p, t));
+        }
+        ret
+      },
+      Expr::Unary(op, operand) => {
+        let t = self.expr(operand);
+        match op {
+          UnaryOp::Neg | UnaryOp::Not => {
+            let neg = *op == UnaryOp::Neg;
+            if !is_int(&t) {
+              self.error(format!("{} only works on numbers, not {}", if neg { "-" } else { "~" }, t));
+              return Type::Unknown;
+            }
+            let t = if t == Type::Bool { Type::I32 } else { t };
+            // ~ keeps the sign bits of a signed number the way they were
+            if neg || t.range().is_some_and(|(low, _)| low == 0) {
+              narrow(e, &t);
+            }
+            t
+          },
+          UnaryOp::BoolNot => {
+            if !is_truthy(&t) {
+              self.error("! needs a value");
+            }
+            Type::Bool
+          },
+          UnaryOp::Deref(width) => {
+            let to = match t {
+              Type::Ptr(to) => *to,
+              Type::Str => Type::U8,
+              Type::Unknown => Type::Unknown,
+              other => {
+                self.error(format!("can't dereference {}, it isn't a pointer", other));
+                Type::Unknown
+              },
+            };
+            *width = to.width();
+            to
+          },
+          UnaryOp::Addr if t == Type::Unknown => Type::Unknown,
+          UnaryOp::Addr => Type::ptr(t),
+        }
+      },
+      Expr::Cond(c, then, otherwise) => {
+        self.cond(c, "if");
+        let a = self.expr(then);
+        let b = self.expr(otherwise);
+        if a == b {
+          a
+        } else if let Some(t) = Self::common(then, &a, otherwise, &b).filter(|_| is_int(&a) && is_int(&b)) {
+          t
+        } else if Self::fits(otherwise, &b, &a) {
+          a
+        } else if Self::fits(then, &a, &b) {
+          b
+        } else {
+          self.error(format!("the two sides of this if are different types, {} and {}", a, b));
+          Type::Unknown
+        }
+      },
+      Expr::Field(value, m) => {
+        let t = self.expr(value);
+        self.field(&t, m)
+      },
+      Expr::Cast(value, to) => {
+        let from = self.expr(value);
+        if !self.check_type(to) {
+          return Type::Unknown;
+        }
+        let ok = match (&from, &*to) {
+          (Type::Void, _) | (_, Type::Void) => false,
+          (Type::Struct(_), _) | (_, Type::Struct(_)) => false,
+          // anything else would have to be compared to 0 first
+          (_, Type::Bool) => from == Type::Bool,
+          _ => true,
+        };
+        if !ok {
+          self.error(format!("can't cast {} to {}", from, to));
+        }
+        to.clone()
+      },
+      // only made by the inliner, which runs after this
+      Expr::Inline(_) => Type::I32,
+    }
+  }
+
+  /// a binary operator, which is rewritten if it's pointer arithmetic or
+  /// depends on the size or sign of its operands
+  fn bin(&mut self, e: &mut Expr) -> Type {
+    use crate::expr::BinOp::*;
+    let (l, op, r) = match e {
+      Expr::Bin(l, op, r) => (l, op, r),
+      _ => unreachable!(),
+    };
+    let lt = self.expr(l);
+    let rt = self.expr(r);
+    let (lp, rp) = (self.pointee_size(&lt), self.pointee_size(&rt));
+    let int = is_int(&lt) && is_int(&rt);
+
+    let t = match *op {
+      _ if lt == Type::Unknown || rt == Type::Unknown => return Type::Unknown,
+      Add | Sub if lp.is_some() && is_int(&rt) => {
+        **r = scale(std::mem::replace(&mut **r, Expr::Lit(0)), lp.unwrap());
+        return lt;
+      },
+      Add if is_int(&lt) && rp.is_some() => {
+        **l = scale(std::mem::replace(&mut **l, Expr::Lit(0)), rp.unwrap());
+        return rt;
+      },
+      Sub if lp.is_some() && lt == rt => {
+        // how many elements apart they are
+        let size = lp.unwrap();
+        if size > 1 {
+          wrap(e, |diff| Expr::Bin(Box::new(diff), Div, Box::new(Expr::Lit(size))));
+        }
+        return Type::I32;
+      },
+      And | Or | Xor if lt == Type::Bool && rt == Type::Bool => return Type::Bool,
+      Less | Greater if lp.is_some() && lt == rt => return Type::Bool,
+      LessUnsigned if int || (lp.is_some() && lt == rt) => return Type::Bool,
+      TestEq if !int && !matches!(lt, Type::Struct(_)) && (Self::fits(l, &lt, &rt) || Self::fits(r, &rt, &lt)) => return Type::Bool,
+      // a shift is the type of what's being shifted
+      Sll | Srl | Sra if int => if lt == Type::Bool { Type::I32 } else { lt },
+      _ if int => match Self::common(l, &lt, r, &rt) {
+        Some(t) => t,
+        None => {
+          self.error(format!("{} doesn't work on {} and {} without converting one of them with `as`", op_name(*op), lt, rt));
+          return Type::Unknown;
+        },
+      },
+      _ => {
+        self.error(format!("{} doesn't work on {} and {}", op_name(*op), lt, rt));
+        return Type::Unknown;
+      },
+    };
+
+    let signed = t.range().is_some_and(|(low, _)| low < 0);
+    match *op {
+      Less | Greater | TestEq => {
+        if t == Type::U32 && *op != TestEq {
+          // a > b is b < a
+          if *op == Greater {
+            std::mem::swap(l, r);
+          }
+          *op = LessUnsigned;
+        }
+        return Type::Bool;
+      },
+      Div if t == Type::U32 => *op = DivUnsigned,
+      Rem if t == Type::U32 => *op = RemUnsigned,
+      Sra if t == Type::U32 => *op = Srl,
+      // the bits above a narrow signed number are copies of its sign, which
+      // mustn't get shifted in
+      Srl if signed && is_narrow(&t) => {
+        let unsigned = if t == Type::I8 { Type::U8 } else { Type::U16 };
+        wrap(l, |l| Expr::Cast(Box::new(l), unsigned));
+      },
+      _ => (),
+    }
+    // the rest can't go out of range, since they start out in it: &, |, ^, %,
+    // and >> and >>_ on unsigned numbers. / can, since x / 0 is -1.
+    if matches!(op, Add | Sub | Mul | Sll | Div) || (*op == Srl && signed) {
+      narrow(e, &t);
+    }
+    t
+  }
+}
+
+fn op_name(op: BinOp) -> &'static str {
+  use crate::expr::BinOp::*;
+  match op {
+    Add => "+",
+    Sub => "-",
+    Mul => "*",
+    Div => "/",
+    Rem => "%",
+    Srl => ">>_",
+    Sra => ">>",
+    Sll => "<<",
+    And => "&",
+    Or => "|",
+    Xor => "^",
+    Less => "<",
+    LessUnsigned => "<_",
+    Greater => ">",
+    TestEq => "==",
+    DivUnsigned => "/",
+    RemUnsigned => "%",
+  }
+}
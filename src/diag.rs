@@ -0,0 +1,79 @@
+use std::fmt::Display;
+
+/// a range of bytes in the source
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize) -> Self {
+    Self { start, end }
+  }
+}
+
+/// a syntax tree node along with where it came from
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+  pub node: T,
+  pub span: Span,
+  /// the `///` comments right before it, one line each, if it's a `let`,
+  /// `fn` or `const`
+  pub doc: Vec<String>,
+}
+
+impl<T> Spanned<T> {
+  pub fn new(node: T, span: Span) -> Self {
+    Self { node, span, doc: vec![] }
+  }
+}
+
+/**
+ * The text being compiled, so that errors can say which line they're on and
+ * show it. Errors look like
+ *
+ * ```text
+ * ERR: 3:1: argument 1 of print_dec should be i32, not str
+ *   3 | print_dec("hello");
+ *     | ^^^^^^^^^^^^^^^^^^
+ * ```
+ */
+pub struct Source<'a> {
+  text: &'a str,
+}
+
+impl<'a> Source<'a> {
+  pub fn new(text: &'a str) -> Self {
+    Self { text }
+  }
+
+  /// line and column of a byte offset, both counting from 1
+  pub fn line_col(&self, offset: usize) -> (usize, usize) {
+    let before = &self.text[..offset.min(self.text.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, col)
+  }
+
+  pub fn error(&self, span: Span, message: impl Display) {
+    self.report("ERR", span, message);
+  }
+
+  pub fn warn(&self, span: Span, message: impl Display) {
+    self.report("WARN", span, message);
+  }
+
+  fn report(&self, level: &str, span: Span, message: impl Display) {
+    let (line, col) = self.line_col(span.start);
+    eprintln!("{}: {}:{}: {}", level, line, col, message);
+
+    // only the first line of a span that goes over several
+    let text = self.text.lines().nth(line - 1).unwrap_or("");
+    let start = col - 1;
+    let end = (span.end.saturating_sub(span.start) + start).clamp(start + 1, text.len().max(start + 1));
+    let number = line.to_string();
+    eprintln!(" {} | {}", number, text);
+    eprintln!(" {} | {}{}", " ".repeat(number.len()), " ".repeat(start), "^".repeat(end - start));
+  }
+}
@@ -1,30 +1,149 @@
 
-pub type Block = Vec<Stmt>;
+use crate::diag::Spanned;
+use crate::intern::Symbol;
 
-#[derive(Debug)]
+pub type Block = Vec<Spanned<Stmt>>;
+
+#[derive(Debug, Clone)]
 pub enum Stmt {
   ExprStmt(Expr),
-  Decl(String, DeclInit),
-  Assignment(String, Expr),
+  /// `let name: type = value;`, where a missing value is 0 and a missing type
+  /// is worked out from the value
+  Decl(Symbol, Option<Type>, Expr),
+  Assignment(Symbol, Expr),
+  /// `*address = value;`, which writes as many bytes as the address points at
+  Store(Expr, Expr, Width),
+  /// `place.field = value;`, where the first one is an `Expr::Field`
+  SetField(Expr, Expr),
   If(Expr, Block, Option<Block>),
-  While(Expr, Block),
+  /// `while`, `for` and `loop` all end up as one of these
+  Loop(Box<Loop>),
+  /// `break;` or `break 'label;`
+  Break(Option<Symbol>),
+  /// `continue;` or `continue 'label;`
+  Continue(Option<Symbol>),
+  /// `match value { pattern => { ... }, ... }`
+  Match(Expr, Vec<Arm>),
+  Fn(FnDecl),
+  /// `struct Name { field: type, ... }`
+  Struct(StructDecl),
+  /// `const NAME = value;`. The type checker puts the value in wherever the
+  /// name is used, and replaces it with a literal.
+  Const(Symbol, Expr),
+  /// `enum Name { A, B = value, ... }`, where each variant is a constant one
+  /// more than the one before it, starting at 0. The type checker fills in
+  /// every value.
+  Enum(Symbol, Vec<(Symbol, Option<Expr>)>),
+  Return(Option<Expr>),
+  /// a statement with a syntax error, which has already been reported
+  Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct StructDecl {
+  pub name: Symbol,
+  pub fields: Vec<(Symbol, Type)>,
+  /// in bytes. The type checker works these out, they're 0 until then.
+  pub size: i64,
+  pub align: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Arm {
+  /// the arm runs if any of these match
+  pub patterns: Vec<Pattern>,
+  pub body: Block,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+  Value(i64),
+  /// `low..=high`, including both ends
+  Range(i64, i64),
+  /// `_`, which matches anything
+  Wildcard,
+  /// the name of a constant. The type checker turns it into a `Value`.
+  Const(Symbol),
+}
+
+#[derive(Debug, Clone)]
+pub struct Loop {
+  /// from `'name:` in front of the loop
+  pub label: Option<Symbol>,
+  /// runs once before the loop starts. Anything declared here is only
+  /// visible inside the loop (`for let i = 0; ...`).
+  pub init: Block,
+  /// checked before every iteration. `None` loops forever.
+  pub cond: Option<Expr>,
+  pub body: Block,
+  /// runs after the body, and on `continue`
+  pub step: Block,
 }
 
 
-#[derive(Debug)]
-pub enum DeclInit {
-  Str(String),
-  Int(i64),
+#[derive(Debug, Clone)]
+pub struct FnDecl {
+  pub name: Symbol,
+  pub params: Vec<Symbol>,
+  /// one for each parameter, `i32` where there was none
+  pub param_types: Vec<Type>,
+  /// from `-> type`, otherwise `i32`
+  pub ret: Type,
+  pub body: Block,
+  pub inline: InlineHint,
 }
 
-#[derive(Debug)]
+/// set with `#[inline]` or `#[noinline]` before a function
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InlineHint {
+  Default,
+  Always,
+  Never,
+}
+
+#[derive(Debug, Clone)]
 pub enum Expr {
   Lit(i64),
-  String(String),
-  Ident(String),
+  /// the bytes of a string, without the 0 at the end
+  String(Vec<u8>),
+  Ident(Symbol),
   Bin(Box<Expr>, BinOp, Box<Expr>),
-  Call(String, Vec<Expr>),
+  Call(Symbol, Vec<Expr>),
   Unary(UnaryOp, Box<Expr>),
+  /// `if c { a } else { b }` or `c ? a : b`
+  Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+  /// `value as type`
+  Cast(Box<Expr>, Type),
+  /// `value.name`. `pointer->name` is parsed as `(*pointer).name`.
+  Field(Box<Expr>, Member),
+  /// the body of a function substituted in place of a call to it. Produced
+  /// by the inliner, never by the parser.
+  Inline(Box<Inlined>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Inlined {
+  /// the function that was inlined, for error messages
+  pub name: Symbol,
+  /// parameters (already renamed so they can't clash with the caller), how
+  /// they're stored, and the arguments they are initialized with
+  pub params: Vec<(Symbol, Width, Expr)>,
+  pub body: Block,
+}
+
+/// a field being read or written. The type checker fills in where it is.
+#[derive(Debug, Clone)]
+pub struct Member {
+  pub name: Symbol,
+  /// bytes from the start of the struct
+  pub offset: i32,
+  pub width: Width,
+}
+
+impl Member {
+  pub fn new(name: Symbol) -> Member {
+    Member { name, offset: 0, width: Width::Word }
+  }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -44,13 +163,171 @@ pub enum BinOp {
   LessUnsigned,
   Greater,
   TestEq,
+  /// `/` and `%` on `u32`. There's no syntax for these, the type checker
+  /// picks them.
+  DivUnsigned,
+  RemUnsigned,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum UnaryOp {
-  Deref,
+  /// `*address`, loading as many bytes as the address points at
+  Deref(Width),
   Addr,
   Neg,
   Not,
   BoolNot,
 }
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+  I8,
+  U8,
+  I16,
+  U16,
+  I32,
+  U32,
+  /// what comparisons and `!` give. It turns into an `i32` (0 or 1) wherever
+  /// one is wanted, but not the other way around.
+  Bool,
+  /// the address of a zero-terminated string
+  Str,
+  /// `*type`
+  Ptr(Box<Type>),
+  /// a `struct` by name. Only variables can be one, they can't be copied
+  /// around as values.
+  Struct(Symbol),
+  /// what standard functions that don't return anything give
+  Void,
+  /// something that already had an error reported. It fits anywhere, so that
+  /// one mistake doesn't cause more errors further out.
+  Unknown,
+}
+
+impl Type {
+  pub fn ptr(to: Type) -> Type {
+    Type::Ptr(Box::new(to))
+  }
+
+  pub fn width(&self) -> Width {
+    match self {
+      Type::I8 => Width::I8,
+      Type::U8 => Width::U8,
+      Type::I16 => Width::I16,
+      Type::U16 => Width::U16,
+      _ => Width::Word,
+    }
+  }
+
+  /// the smallest and largest value of an integer type
+  pub fn range(&self) -> Option<(i64, i64)> {
+    match self {
+      Type::I8 => Some((i8::MIN as i64, i8::MAX as i64)),
+      Type::U8 => Some((0, u8::MAX as i64)),
+      Type::I16 => Some((i16::MIN as i64, i16::MAX as i64)),
+      Type::U16 => Some((0, u16::MAX as i64)),
+      Type::I32 => Some((i32::MIN as i64, i32::MAX as i64)),
+      Type::U32 => Some((0, u32::MAX as i64)),
+      Type::Bool => Some((0, 1)),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * How a value is kept in memory. Registers always hold all 32 bits, so a
+ * value narrower than that is sign or zero extended when it's loaded, and
+ * arithmetic on it gets cut back down to size by the type checker.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Width {
+  #[default]
+  Word,
+  I8,
+  U8,
+  I16,
+  U16,
+}
+
+impl Width {
+  pub fn bytes(self) -> i64 {
+    match self {
+      Width::Word => 4,
+      Width::I8 | Width::U8 => 1,
+      Width::I16 | Width::U16 => 2,
+    }
+  }
+
+  pub fn load(self) -> &'static str {
+    match self {
+      Width::Word => "lw",
+      Width::I8 => "lb",
+      Width::U8 => "lbu",
+      Width::I16 => "lh",
+      Width::U16 => "lhu",
+    }
+  }
+
+  pub fn store(self) -> &'static str {
+    match self {
+      Width::Word => "sw",
+      Width::I8 | Width::U8 => "sb",
+      Width::I16 | Width::U16 => "sh",
+    }
+  }
+
+  /// the directive for a variable of this width in .data
+  pub fn directive(self) -> &'static str {
+    match self {
+      Width::Word => ".word",
+      Width::I8 | Width::U8 => ".byte",
+      Width::I16 | Width::U16 => ".half",
+    }
+  }
+}
+
+impl std::fmt::Display for Type {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Type::I8 => write!(f, "i8"),
+      Type::U8 => write!(f, "u8"),
+      Type::I16 => write!(f, "i16"),
+      Type::U16 => write!(f, "u16"),
+      Type::I32 => write!(f, "i32"),
+      Type::U32 => write!(f, "u32"),
+      Type::Bool => write!(f, "bool"),
+      Type::Str => write!(f, "str"),
+      Type::Ptr(to) => write!(f, "*{}", to),
+      Type::Struct(name) => write!(f, "{}", name),
+      Type::Void => write!(f, "()"),
+      Type::Unknown => write!(f, "{{unknown}}"),
+    }
+  }
+}
+
+/**
+ * Documentation for a program, as markdown: every top-level `let`, `fn` and
+ * `const` that has a `///` comment, in the order they're written.
+ */
+pub fn docs(ast: &Block) -> String {
+  let mut out = String::new();
+  for s in ast.iter().filter(|s| !s.doc.is_empty()) {
+    let heading = match &s.node {
+      Stmt::Fn(f) => {
+        let params: Vec<String> = f.params.iter().zip(&f.param_types)
+          .map(|(p, t)| format!("{}: {}", p, t))
+          .collect();
+        format!("fn {}({}) -> {}", f.name, params.join(", "), f.ret)
+      },
+      Stmt::Decl(name, Some(ty), _) => format!("let {}: {}", name, ty),
+      Stmt::Decl(name, None, _) => format!("let {}", name),
+      Stmt::Const(name, _) => format!("const {}", name),
+      _ => continue,
+    };
+    if !out.is_empty() {
+      out.push('\n');
+    }
+    out.push_str(&format!("### `{}`\n\n{}\n", heading, s.doc.join("\n")));
+  }
+  out
+}
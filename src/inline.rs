@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::expr::*;
+use crate::intern::Symbol;
+
+/// how many times inlined bodies get another look for calls they can inline.
+const MAX_ROUNDS: usize = 3;
+
+/**
+ * Replaces calls to small functions with a copy of the function's body. A
+ * function gets inlined if it's marked `#[inline]`, or if its body has at most
+ * `threshold` AST nodes, unless it's marked `#[noinline]` or can end up
+ * calling itself. Parameters and locals of the copy are renamed so they can't
+ * clash with the caller's variables, and labels come out fresh anyway because
+ * the code generator makes new ones each time it compiles the body.
+ *
+ * Functions that end up with no calls left are still in the program, the
+ * code generator just won't emit them.
+ */
+pub fn inline(program: &mut Block, threshold: usize) {
+  let fns: HashMap<Symbol, &FnDecl> = program.iter().filter_map(|s| match &s.node {
+    Stmt::Fn(f) => Some((f.name, f)),
+    _ => None,
+  }).collect();
+
+  let candidates: HashMap<Symbol, Candidate> = fns.values()
+    .filter(|f| f.inline != InlineHint::Never)
+    .filter(|f| f.inline == InlineHint::Always || size(&f.body) <= threshold)
+    .filter(|f| !f.body.iter().any(|s| matches!(s.node, Stmt::Fn(_))))
+    .filter(|f| !calls_itself(&f.name, &fns))
+    .map(|f| (f.name, Candidate {
+      decl: (*f).clone(),
+      globals: free_vars(f),
+    }))
+    .collect();
+  if candidates.is_empty() {
+    return;
+  }
+
+  let mut inliner = Inliner { candidates, count: 0, changed: false };
+  for _ in 0..MAX_ROUNDS {
+    inliner.changed = false;
+    for stmt in program.iter_mut() {
+      match &mut stmt.node {
+        Stmt::Fn(f) => {
+          let locals = declared_names(f);
+          inliner.block(&mut f.body, &locals);
+        },
+        // a `let` in a block at the top level can shadow a global too
+        other => {
+          let mut locals = HashSet::new();
+          declared_inside(other, &mut locals);
+          inliner.stmt(other, &locals);
+        },
+      }
+    }
+    if !inliner.changed {
+      break;
+    }
+  }
+}
+
+struct Candidate {
+  decl: FnDecl,
+  /// variables the body uses without declaring them, which have to be globals
+  globals: HashSet<Symbol>,
+}
+
+struct Inliner {
+  candidates: HashMap<Symbol, Candidate>,
+  count: usize,
+  changed: bool,
+}
+
+impl Inliner {
+  /// `locals` are the names declared anywhere in the function being rewritten.
+  fn block(&mut self, b: &mut Block, locals: &HashSet<Symbol>) {
+    b.iter_mut().for_each(|s| self.stmt(&mut s.node, locals));
+  }
+
+  fn stmt(&mut self, s: &mut Stmt, locals: &HashSet<Symbol>) {
+    match s {
+      Stmt::ExprStmt(e) | Stmt::Decl(_, _, e) | Stmt::Assignment(_, e) | Stmt::Return(Some(e)) => self.expr(e, locals),
+      Stmt::If(cond, t, f) => {
+        self.expr(cond, locals);
+        self.block(t, locals);
+        if let Some(f) = f {
+          self.block(f, locals);
+        }
+      },
+      Stmt::Loop(l) => {
+        self.block(&mut l.init, locals);
+        if let Some(cond) = &mut l.cond {
+          self.expr(cond, locals);
+        }
+        self.block(&mut l.body, locals);
+        self.block(&mut l.step, locals);
+      },
+      Stmt::Store(addr, value, _) | Stmt::SetField(addr, value) => {
+        self.expr(addr, locals);
+        self.expr(value, locals);
+      },
+      Stmt::Match(value, arms) => {
+        self.expr(value, locals);
+        arms.iter_mut().for_each(|a| self.block(&mut a.body, locals));
+      },
+      Stmt::Fn(_) | Stmt::Struct(_) | Stmt::Const(..) | Stmt::Enum(..) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Return(None) | Stmt::Error => (),
+    }
+  }
+
+  fn expr(&mut self, e: &mut Expr, locals: &HashSet<Symbol>) {
+    match e {
+      Expr::Bin(l, _, r) => {
+        self.expr(l, locals);
+        self.expr(r, locals);
+      },
+      Expr::Unary(_, e) | Expr::Cast(e, _) | Expr::Field(e, _) => self.expr(e, locals),
+      Expr::Cond(c, t, f) => {
+        self.expr(c, locals);
+        self.expr(t, locals);
+        self.expr(f, locals);
+      },
+      Expr::Inline(inl) => {
+        inl.params.iter_mut().for_each(|(_, _, e)| self.expr(e, locals));
+        self.block(&mut inl.body, locals);
+      },
+      Expr::Call(name, args) => {
+        args.iter_mut().for_each(|a| self.expr(a, locals));
+        let decl = match self.candidates.get(name) {
+          Some(c) => &c.decl,
+          None => return,
+        };
+        // the wrong number of arguments was reported by the type checker.
+        // if the caller has a local with the same name as a global the callee
+        // uses, the copy would end up reading the local instead, so leave it.
+        if decl.params.len() != args.len() || !self.candidates[name].globals.is_disjoint(locals) {
+          return;
+        }
+
+        let mut renamer = Renamer::new(format!("__inline_{}_", self.count + 1));
+        let params = decl.params.iter().zip(&decl.param_types).zip(std::mem::take(args))
+          .map(|((p, t), arg)| (renamer.declare(p), t.width(), arg))
+          .collect();
+        let mut body = decl.body.clone();
+        renamer.block(&mut body);
+        self.count += 1;
+
+        *e = Expr::Inline(Box::new(Inlined { name: *name, params, body }));
+        self.changed = true;
+      },
+      Expr::Lit(_) | Expr::String(_) | Expr::Ident(_) => (),
+    }
+  }
+}
+
+/// gives every variable declared in a copied function body a new name.
+struct Renamer {
+  prefix: String,
+  scopes: Vec<HashMap<Symbol, Symbol>>,
+  /// how many times each name has been declared so far
+  declared: HashMap<Symbol, usize>,
+}
+
+impl Renamer {
+  fn new(prefix: String) -> Self {
+    Self { prefix, scopes: vec![HashMap::new()], declared: HashMap::new() }
+  }
+
+  fn declare(&mut self, name: &Symbol) -> Symbol {
+    // a name declared again (shadowing a parameter, say) still needs to be
+    // different from the first one. # can't be part of an identifier.
+    let n = self.declared.entry(*name).or_insert(0);
+    *n += 1;
+    let new = match *n {
+      1 => Symbol::intern(&format!("{}{}", self.prefix, name)),
+      n => Symbol::intern(&format!("{}{}#{}", self.prefix, name, n)),
+    };
+    self.scopes.last_mut().unwrap().insert(*name, new);
+    new
+  }
+
+  fn lookup(&self, name: &mut Symbol) {
+    if let Some(new) = self.scopes.iter().rev().find_map(|s| s.get(name)) {
+      *name = *new;
+    }
+  }
+
+  fn block(&mut self, b: &mut Block) {
+    self.scopes.push(HashMap::new());
+    b.iter_mut().for_each(|s| self.stmt(&mut s.node));
+    self.scopes.pop();
+  }
+
+  fn stmt(&mut self, s: &mut Stmt) {
+    match s {
+      Stmt::Decl(name, _, e) => {
+        // the initial value can't see the variable it's initializing
+        self.expr(e);
+        *name = self.declare(name);
+      },
+      Stmt::Assignment(name, e) => {
+        self.expr(e);
+        self.lookup(name);
+      },
+      Stmt::ExprStmt(e) | Stmt::Return(Some(e)) => self.expr(e),
+      Stmt::If(cond, t, f) => {
+        self.expr(cond);
+        self.block(t);
+        if let Some(f) = f {
+          self.block(f);
+        }
+      },
+      Stmt::Loop(l) => {
+        // what `init` declares is visible for the rest of the loop
+        self.scopes.push(HashMap::new());
+        l.init.iter_mut().for_each(|s| self.stmt(&mut s.node));
+        if let Some(cond) = &mut l.cond {
+          self.expr(cond);
+        }
+        self.block(&mut l.body);
+        self.block(&mut l.step);
+        self.scopes.pop();
+      },
+      Stmt::Store(addr, value, _) | Stmt::SetField(addr, value) => {
+        self.expr(addr);
+        self.expr(value);
+      },
+      Stmt::Match(value, arms) => {
+        self.expr(value);
+        arms.iter_mut().for_each(|a| self.block(&mut a.body));
+      },
+      Stmt::Fn(_) | Stmt::Struct(_) | Stmt::Const(..) | Stmt::Enum(..) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Return(None) | Stmt::Error => (),
+    }
+  }
+
+  fn expr(&mut self, e: &mut Expr) {
+    match e {
+      Expr::Ident(name) => self.lookup(name),
+      Expr::Bin(l, _, r) => {
+        self.expr(l);
+        self.expr(r);
+      },
+      Expr::Unary(_, e) | Expr::Cast(e, _) | Expr::Field(e, _) => self.expr(e),
+      Expr::Cond(c, t, f) => {
+        self.expr(c);
+        self.expr(t);
+        self.expr(f);
+      },
+      Expr::Call(_, args) => args.iter_mut().for_each(|a| self.expr(a)),
+      Expr::Inline(inl) => {
+        // the names in here were already made unique when it was inlined.
+        inl.params.iter_mut().for_each(|(_, _, e)| self.expr(e));
+        self.block(&mut inl.body);
+      },
+      Expr::Lit(_) | Expr::String(_) => (),
+    }
+  }
+}
+
+/// number of statements and expressions in a block, as a rough measure of code size.
+pub fn size(b: &Block) -> usize {
+  fn expr(e: &Expr) -> usize {
+    1 + match e {
+      Expr::Bin(l, _, r) => expr(l) + expr(r),
+      Expr::Unary(_, e) | Expr::Cast(e, _) | Expr::Field(e, _) => expr(e),
+      Expr::Cond(c, t, f) => expr(c) + expr(t) + expr(f),
+      Expr::Call(_, args) => args.iter().map(expr).sum(),
+      Expr::Inline(inl) => inl.params.iter().map(|(_, _, e)| expr(e)).sum::<usize>() + size(&inl.body),
+      Expr::Lit(_) | Expr::String(_) | Expr::Ident(_) => 0,
+    }
+  }
+  b.iter().map(|s| 1 + match &s.node {
+    Stmt::ExprStmt(e) | Stmt::Decl(_, _, e) | Stmt::Assignment(_, e) | Stmt::Return(Some(e)) => expr(e),
+    Stmt::If(cond, t, f) => expr(cond) + size(t) + f.as_ref().map_or(0, size),
+    Stmt::Loop(l) => size(&l.init) + l.cond.as_ref().map_or(0, expr) + size(&l.body) + size(&l.step),
+    Stmt::Store(addr, value, _) | Stmt::SetField(addr, value) => expr(addr) + expr(value),
+    Stmt::Match(value, arms) => expr(value) + arms.iter().map(|a| size(&a.body)).sum::<usize>(),
+    Stmt::Fn(f) => size(&f.body),
+    Stmt::Struct(_) | Stmt::Const(..) | Stmt::Enum(..) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Return(None) | Stmt::Error => 0,
+  }).sum()
+}
+
+/// calls `f` for every call and variable in a block, in no particular order.
+fn walk(b: &Block, f: &mut impl FnMut(&Expr)) {
+  fn expr(e: &Expr, f: &mut impl FnMut(&Expr)) {
+    f(e);
+    match e {
+      Expr::Bin(l, _, r) => {
+        expr(l, f);
+        expr(r, f);
+      },
+      Expr::Unary(_, e) | Expr::Cast(e, _) | Expr::Field(e, _) => expr(e, f),
+      Expr::Cond(c, t, e) => {
+        expr(c, f);
+        expr(t, f);
+        expr(e, f);
+      },
+      Expr::Call(_, args) => args.iter().for_each(|a| expr(a, f)),
+      Expr::Inline(inl) => {
+        inl.params.iter().for_each(|(_, _, e)| expr(e, f));
+        walk(&inl.body, f);
+      },
+      Expr::Lit(_) | Expr::String(_) | Expr::Ident(_) => (),
+    }
+  }
+  for s in b {
+    match &s.node {
+      Stmt::ExprStmt(e) | Stmt::Decl(_, _, e) | Stmt::Return(Some(e)) => expr(e, f),
+      Stmt::Assignment(name, e) => {
+        f(&Expr::Ident(*name));
+        expr(e, f);
+      },
+      Stmt::If(cond, t, e) => {
+        expr(cond, f);
+        walk(t, f);
+        if let Some(e) = e {
+          walk(e, f);
+        }
+      },
+      Stmt::Loop(l) => {
+        walk(&l.init, f);
+        if let Some(cond) = &l.cond {
+          expr(cond, f);
+        }
+        walk(&l.body, f);
+        walk(&l.step, f);
+      },
+      Stmt::Store(addr, value, _) | Stmt::SetField(addr, value) => {
+        expr(addr, f);
+        expr(value, f);
+      },
+      Stmt::Match(value, arms) => {
+        expr(value, f);
+        arms.iter().for_each(|a| walk(&a.body, f));
+      },
+      Stmt::Fn(_) | Stmt::Struct(_) | Stmt::Const(..) | Stmt::Enum(..) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Return(None) | Stmt::Error => (),
+    }
+  }
+}
+
+/// parameters and every variable declared with `let` anywhere in `f`.
+fn declared_names(f: &FnDecl) -> HashSet<Symbol> {
+  let mut names: HashSet<Symbol> = f.params.iter().cloned().collect();
+  declared_in(&f.body, &mut names);
+  names
+}
+
+/// every variable declared with `let` in `b`, or in a block inside of it.
+fn declared_in(b: &Block, names: &mut HashSet<Symbol>) {
+  for s in b {
+    if let Stmt::Decl(name, _, _) = &s.node {
+      names.insert(*name);
+    }
+    declared_inside(&s.node, names);
+  }
+}
+
+/// every variable declared in the blocks that are part of `s`.
+fn declared_inside(s: &Stmt, names: &mut HashSet<Symbol>) {
+  match s {
+    Stmt::If(_, t, f) => {
+      declared_in(t, names);
+      if let Some(f) = f {
+        declared_in(f, names);
+      }
+    },
+    Stmt::Loop(l) => {
+      declared_in(&l.init, names);
+      declared_in(&l.body, names);
+    },
+    Stmt::Match(_, arms) => arms.iter().for_each(|a| declared_in(&a.body, names)),
+    _ => (),
+  }
+}
+
+/**
+ * The variables `f` uses without declaring them, i.e. globals. Everything `f`
+ * declares gets renamed with a `#` (which can't be in an identifier) in front,
+ * so whatever is left unrenamed is free. Doing it this way gets scoping right,
+ * e.g. in `let x = x + 1;` the second x is a global.
+ */
+fn free_vars(f: &FnDecl) -> HashSet<Symbol> {
+  let mut renamer = Renamer::new("#".to_string());
+  f.params.iter().for_each(|p| { renamer.declare(p); });
+  let mut body = f.body.clone();
+  renamer.block(&mut body);
+
+  let mut free = HashSet::new();
+  walk(&body, &mut |e| {
+    if let Expr::Ident(name) = e {
+      if !name.as_str().starts_with('#') {
+        free.insert(*name);
+      }
+    }
+  });
+  free
+}
+
+/// true if calling `name` can lead back to another call to `name`.
+fn calls_itself(name: &Symbol, fns: &HashMap<Symbol, &FnDecl>) -> bool {
+  let mut seen = HashSet::new();
+  let mut stack = vec![*name];
+  while let Some(f) = stack.pop() {
+    let decl = match fns.get(&f) {
+      Some(d) => d,
+      None => continue, // standard library
+    };
+    let mut found = false;
+    walk(&decl.body, &mut |e| {
+      if let Expr::Call(callee, _) = e {
+        if callee == name {
+          found = true;
+        } else if seen.insert(*callee) {
+          stack.push(*callee);
+        }
+      }
+    });
+    if found {
+      return true;
+    }
+  }
+  false
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/**
+ * A name from the program, as a number. Each name is only stored once, so
+ * comparing or hashing two of them is comparing numbers, and copying one
+ * doesn't allocate. The text is looked up when it's needed, for errors and
+ * labels.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+struct Interner {
+  ids: HashMap<&'static str, Symbol>,
+  count: usize,
+}
+
+/// one for the whole compiler, since names never go away while it runs
+fn interner() -> &'static Mutex<Interner> {
+  static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+  INTERNER.get_or_init(|| Mutex::new(Interner { ids: HashMap::new(), count: 0 }))
+}
+
+const CHUNK: usize = 1024;
+
+/// the text of each symbol, by number. Interning is the only thing that
+/// writes, while holding the lock, and a slot is never changed once it's set,
+/// so reading it back doesn't need the lock. It's in chunks that are made as
+/// they're needed, which is room for 4M names.
+static NAMES: [OnceLock<Box<[OnceLock<&'static str>]>>; 4096] = [const { OnceLock::new() }; 4096];
+
+impl Symbol {
+  pub fn intern(name: &str) -> Symbol {
+    let mut i = interner().lock().unwrap();
+    if let Some(&s) = i.ids.get(name) {
+      return s;
+    }
+    let s = Symbol(i.count as u32);
+    let name: &'static str = Box::leak(name.into());
+    let chunk = NAMES.get(i.count / CHUNK).expect("too many names");
+    let _ = chunk.get_or_init(|| (0..CHUNK).map(|_| OnceLock::new()).collect())[i.count % CHUNK].set(name);
+    i.count += 1;
+    i.ids.insert(name, s);
+    s
+  }
+
+  pub fn as_str(self) -> &'static str {
+    let i = self.0 as usize;
+    NAMES[i / CHUNK].get().and_then(|chunk| chunk[i % CHUNK].get()).unwrap()
+  }
+}
+
+impl From<&str> for Symbol {
+  fn from(name: &str) -> Symbol {
+    Symbol::intern(name)
+  }
+}
+
+impl PartialEq<&str> for Symbol {
+  fn eq(&self, other: &&str) -> bool {
+    self.as_str() == *other
+  }
+}
+
+impl std::fmt::Display for Symbol {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl std::fmt::Debug for Symbol {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}", self.as_str())
+  }
+}
@@ -1,3 +1,11 @@
 pub mod riscv;
+pub mod asm;
+pub mod opt;
+pub mod inline;
+pub mod passes;
+pub mod stdlib;
+pub mod intern;
 pub mod expr;
+pub mod diag;
+pub mod check;
 pub mod parse;
\ No newline at end of file
@@ -1,17 +1,107 @@
+use compiler::riscv::Target;
+use compiler::passes::{OptLevel, Pass, PassManager};
+
+const USAGE: &str = "usage: compiler [options] <file.oh>
+
+options:
+  --target rv32im|rv32i   instruction set to generate code for (default rv32im)
+  -O0, -O1, -O2, -Os      how hard to optimize (default -O1)
+  --passes=a,b,...        run exactly these optimization passes
+  --list-passes           print every optimization pass and exit
+  --time-passes           print how long each pass took
+  --docs                  print the file's /// comments as markdown and exit
+  --stdlib-docs           print the standard library reference and exit
+  --help                  print this and exit";
+
+/// a bad command line, which isn't the program's fault
+fn usage_error(message: impl std::fmt::Display) -> ! {
+  eprintln!("ERR: {}", message);
+  eprintln!("run with --help to see the options");
+  std::process::exit(2);
+}
+
 fn main() {
   let args: Vec<String> = std::env::args().collect();
   let mut args = args.iter();
   args.next();
-  let path = args.next().expect("expected file path to compile");
 
-  let text = std::fs::read_to_string(path).expect(&format!("file {} not found.", path));
+  let mut path = None;
+  let mut target = Target::Rv32im;
+  let mut opt_level = OptLevel::O1;
+  let mut passes: Option<Vec<Pass>> = None;
+  let mut time_passes = false;
+  let mut docs = false;
+  while let Some(arg) = args.next() {
+    if arg == "--target" {
+      let t = args.next().unwrap_or_else(|| usage_error("expected a target after --target"));
+      target = t.parse().unwrap_or_else(|e| usage_error(e));
+    } else if let Some(t) = arg.strip_prefix("--target=") {
+      target = t.parse().unwrap_or_else(|e| usage_error(e));
+    } else if let Some(level) = arg.strip_prefix("-O") {
+      opt_level = level.parse().unwrap_or_else(|e| usage_error(e));
+    } else if let Some(list) = arg.strip_prefix("--passes=") {
+      passes = Some(list.split(',').filter(|p| !p.is_empty())
+        .map(|p| p.parse().unwrap_or_else(|e| usage_error(e)))
+        .collect());
+    } else if arg == "--help" || arg == "-h" {
+      println!("{}", USAGE);
+      return;
+    } else if arg == "--time-passes" {
+      time_passes = true;
+    } else if arg == "--stdlib-docs" {
+      print!("{}", compiler::stdlib::reference_docs());
+      return;
+    } else if arg == "--docs" {
+      docs = true;
+    } else if arg == "--list-passes" {
+      for p in Pass::ALL {
+        println!("{:<10} {}", p.name(), p.description());
+      }
+      return;
+    } else if arg.starts_with('-') {
+      usage_error(format!("unknown option {}", arg));
+    } else if path.is_some() {
+      usage_error("only one file can be compiled at a time");
+    } else {
+      path = Some(arg);
+    }
+  }
+  let path = path.unwrap_or_else(|| usage_error("expected a file to compile"));
+
+  let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+    eprintln!("ERR: can't read {}: {}", path, e);
+    std::process::exit(1);
+  });
 
-  let ast = compiler::parse::parse(&text).expect("parse failed");
+  let (mut ast, errors) = compiler::parse::parse(&text);
+  if docs {
+    print!("{}", compiler::expr::docs(&ast));
+    return;
+  }
+  // a program with syntax errors has holes in it, which anything the type
+  // checker said would mostly be about
+  if errors > 0 {
+    eprintln!("ERR: {} syntax error{}, not compiling", errors, if errors == 1 { "" } else { "s" });
+    std::process::exit(1);
+  }
+  let errors = compiler::check::check(&mut ast, &compiler::diag::Source::new(&text));
+  if errors > 0 {
+    eprintln!("ERR: {} error{}, not compiling", errors, if errors == 1 { "" } else { "s" });
+    std::process::exit(1);
+  }
 
   // for debugging the parser:
   // println!("{:#?}", ast);
 
   let mut builder = compiler::riscv::Compiler::new();
+  builder.set_target(target);
+  let mut pm = PassManager::new(opt_level);
+  if let Some(passes) = passes {
+    pm.set_passes(passes);
+  }
+  pm.set_time_passes(time_passes);
+  builder.set_pass_manager(pm);
   builder.compile(ast);
   builder.dump();
+  builder.pass_manager().report();
 }
@@ -0,0 +1,596 @@
+use std::collections::HashMap;
+
+use crate::asm::{Instr, RegSet, CALLEE_SAVED};
+use crate::riscv::{Reg, SP, A7, T0, T1, T2, T3, T4, T5, T6};
+
+/**
+ * For each instruction, the set of registers whose value may still be read
+ * after it runs. Nothing is considered live once execution runs off the end
+ * of the list.
+ */
+pub fn liveness(instrs: &[Instr]) -> Vec<RegSet> {
+  let labels = label_indices(instrs);
+  let succs: Vec<Vec<usize>> = instrs.iter().enumerate().map(|(i, instr)| {
+    let mut s = vec![];
+    if instr.falls_through() && i + 1 < instrs.len() {
+      s.push(i + 1);
+    }
+    s.extend(instr.targets().iter().filter_map(|l| labels.get(l.as_str()).copied()));
+    s
+  }).collect();
+
+  let mut live_in = vec![RegSet::default(); instrs.len()];
+  let mut live_out = vec![RegSet::default(); instrs.len()];
+  let mut changed = true;
+  while changed {
+    changed = false;
+    for i in (0..instrs.len()).rev() {
+      let out = succs[i].iter().fold(RegSet::default(), |acc, &s| acc.union(live_in[s]));
+      let inn = instrs[i].uses().union(out.minus(instrs[i].defs()));
+      if out != live_out[i] || inn != live_in[i] {
+        live_out[i] = out;
+        live_in[i] = inn;
+        changed = true;
+      }
+    }
+  }
+  live_out
+}
+
+/// label name -> index of the label in `instrs`
+pub fn label_indices(instrs: &[Instr]) -> HashMap<&str, usize> {
+  instrs.iter().enumerate().filter_map(|(i, instr)| match instr {
+    Instr::Label(l) => Some((l.as_str(), i)),
+    _ => None,
+  }).collect()
+}
+
+/// every register mentioned anywhere in `instrs`
+fn mentioned_regs(instrs: &[Instr]) -> RegSet {
+  instrs.iter().fold(RegSet::default(), |acc, i| acc.union(i.defs()).union(i.uses()))
+}
+
+/**
+ * A loop in the instruction list: the header label and the last branch back
+ * to it. Everything in between (inclusive) is the loop body.
+ */
+struct Loop {
+  header: usize,
+  latch: usize,
+}
+
+fn find_loop(instrs: &[Instr], header: &str) -> Option<Loop> {
+  let h = instrs.iter().position(|i| matches!(i, Instr::Label(l) if l == header))?;
+  let latch = instrs.iter().rposition(|i| i.targets().iter().any(|t| t == header))?;
+  if latch < h {
+    return None;
+  }
+  Some(Loop { header: h, latch })
+}
+
+/**
+ * Loop-invariant code motion. Any pure computation inside a loop whose
+ * operands are not changed by the loop (constants, addresses, and loads of
+ * variables the loop never stores to) is moved into a preheader in front of
+ * the loop and kept in a callee-saved register, which the code generator
+ * never hands out otherwise.
+ *
+ * Loops are found by their back edges, so this works on anything shaped like
+ * a loop, not just `while`. Inner loops are handled first so their invariants
+ * can keep moving outwards.
+ */
+pub fn licm(instrs: &mut Vec<Instr>) {
+  let taken = mentioned_regs(instrs);
+  let mut pool: Vec<Reg> = CALLEE_SAVED.minus(taken).iter().collect();
+  pool.reverse(); // hand out s1 first
+  let mut hoisted = RegSet::default();
+
+  let labels = &label_indices(instrs);
+  let mut headers: Vec<(usize, String)> = instrs.iter().enumerate()
+    .flat_map(|(i, instr)| instr.targets().iter().filter_map(move |target| {
+      let h = *labels.get(target.as_str())?;
+      if h < i { Some((i - h, target.to_string())) } else { None }
+    }))
+    .collect();
+  // innermost (smallest) loops first
+  headers.sort();
+  headers.dedup_by(|a, b| a.1 == b.1);
+
+  for (_, header) in headers {
+    hoist_loop(instrs, &header, &mut pool, &mut hoisted);
+  }
+}
+
+fn hoist_loop(instrs: &mut Vec<Instr>, header: &str, pool: &mut Vec<Reg>, hoisted: &mut RegSet) {
+  let Loop { header: h, latch } = match find_loop(instrs, header) {
+    Some(l) => l,
+    None => return,
+  };
+
+  // the loop may only be entered by falling into the header, or by the jump
+  // right in front of it (which is how rotated loops get to their condition).
+  let inner_labels: Vec<&str> = instrs[h..=latch].iter().filter_map(|i| match i {
+    Instr::Label(l) => Some(l.as_str()),
+    _ => None,
+  }).collect();
+  let entry_jump = h > 0 && matches!(&instrs[h - 1], Instr::Jump(l) if inner_labels.contains(&l.as_str()));
+  let other_entries = instrs.iter().enumerate()
+    .filter(|(i, _)| *i < h || *i > latch)
+    .filter(|(i, _)| !(entry_jump && *i == h - 1))
+    .any(|(_, instr)| instr.targets().iter().any(|t| inner_labels.contains(&t.as_str())));
+  if other_entries {
+    return;
+  }
+  let preheader_at = if entry_jump { h - 1 } else { h };
+
+  let body = &instrs[h..=latch];
+  let clobbers_memory = body.iter().any(|i| matches!(i, Instr::Store(..) | Instr::Call(..) | Instr::Ecall));
+  let stored_syms: Vec<String> = body.iter().filter_map(|i| match i {
+    Instr::StoreSym(_, _, sym, _) => Some(sym.clone()),
+    _ => None,
+  }).collect();
+
+  let mut preheader: Vec<Instr> = vec![];
+  // value (with the destination register zeroed out) -> register holding it
+  let mut values: HashMap<Instr, Reg> = HashMap::new();
+  let mut latch = latch;
+
+  loop {
+    let defined_in_loop = instrs[h..=latch].iter().fold(RegSet::default(), |acc, i| acc.union(i.defs()));
+    let candidate = (h..=latch).find_map(|i| {
+      let instr = &instrs[i];
+      let invariant_load = match instr {
+        Instr::LoadSym(_, _, sym) => !clobbers_memory && !stored_syms.contains(sym),
+        _ => false,
+      };
+      if !(instr.is_pure() || invariant_load) || instr.uses().0 & defined_in_loop.0 != 0 {
+        return None;
+      }
+      // this is a copy left behind by an earlier hoist (maybe from an earlier
+      // run), so there is nothing to gain.
+      if matches!(instr, Instr::Mv(_, src) if hoisted.contains(*src) || CALLEE_SAVED.contains(*src)) {
+        return None;
+      }
+      let rd = instr.defs().iter().next()?;
+      let mut key = instr.clone();
+      key.set_def(crate::riscv::ZERO);
+      if hoisted.contains(rd) || values.contains_key(&key) || !pool.is_empty() {
+        Some((i, rd, key))
+      } else {
+        None // out of registers, leave it where it is
+      }
+    });
+    let (i, rd, key) = match candidate {
+      Some(c) => c,
+      None => break,
+    };
+
+    // registers from an inner loop's preheader are only ever written once,
+    // so those instructions can be moved as-is.
+    if hoisted.contains(rd) {
+      let defined_once = instrs[h..=latch].iter().filter(|i| i.defs().contains(rd)).count() == 1;
+      if defined_once {
+        preheader.push(instrs.remove(i));
+        latch -= 1;
+        continue;
+      }
+    }
+
+    let reg = match values.get(&key) {
+      Some(r) => *r,
+      None => {
+        let r = pool.pop().expect("candidate was checked for a free register");
+        let mut moved = instrs[i].clone();
+        moved.set_def(r);
+        preheader.push(moved);
+        values.insert(key, r);
+        *hoisted = hoisted.with(r);
+        r
+      },
+    };
+
+    // point the rest of the block at the hoisted register. If the old
+    // register is still needed past the end of the block, leave a copy.
+    let mut still_live = true;
+    let mut last = i;
+    for (j, instr) in instrs.iter_mut().enumerate().take(latch + 1).skip(i + 1) {
+      if matches!(instr, Instr::Label(_)) {
+        break;
+      }
+      instr.map_uses(|u| if u == rd { reg } else { u });
+      if instr.uses().contains(rd) {
+        // a call reading it as an argument, which can't be renamed. rd is
+        // live right before this, so the copy stays.
+        break;
+      }
+      last = j;
+      if instr.defs().contains(rd) {
+        still_live = false;
+        break;
+      }
+      if instr.ends_block() {
+        break;
+      }
+    }
+    if still_live && liveness(instrs)[last].contains(rd) {
+      instrs[i] = Instr::Mv(rd, reg);
+    } else {
+      instrs.remove(i);
+      latch -= 1;
+    }
+  }
+
+  for (n, instr) in preheader.into_iter().enumerate() {
+    instrs.insert(preheader_at + n, instr);
+  }
+}
+
+/**
+ * What a register holds, in terms of other value numbers. Two instructions
+ * that produce the same `Value` compute the same thing.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Value {
+  Const(i64),
+  Addr(String),
+  /// the contents of a variable, and the instruction that loads it
+  Sym(&'static str, String),
+  /// something loaded from an offset off of another value
+  Mem(&'static str, i32, u32),
+  Op(&'static str, u32, u32),
+  OpImm(&'static str, u32, i64),
+  Unary(&'static str, u32),
+}
+
+impl Value {
+  fn is_memory(&self) -> bool {
+    matches!(self, Value::Sym(..) | Value::Mem(..))
+  }
+}
+
+/// operations where the order of the operands doesn't matter
+const COMMUTATIVE: &[&str] = &["add", "mul", "and", "or", "xor"];
+
+#[derive(Default)]
+struct ValueTable {
+  next: u32,
+  values: HashMap<Value, u32>,
+  regs: [Option<u32>; 32],
+}
+
+impl ValueTable {
+  fn fresh(&mut self) -> u32 {
+    self.next += 1;
+    self.next
+  }
+
+  /// the value number held in `r`, making one up if it isn't known yet.
+  fn of(&mut self, r: Reg) -> u32 {
+    match self.regs[r.0 as usize] {
+      Some(vn) => vn,
+      None => {
+        let vn = if r == crate::riscv::ZERO { self.number(Value::Const(0)) } else { self.fresh() };
+        self.regs[r.0 as usize] = Some(vn);
+        vn
+      },
+    }
+  }
+
+  fn number(&mut self, v: Value) -> u32 {
+    if let Some(vn) = self.values.get(&v) {
+      return *vn;
+    }
+    let vn = self.fresh();
+    self.values.insert(v, vn);
+    vn
+  }
+
+  /// the first register currently holding `vn`, if any. x0 wins if it matches.
+  fn holder(&self, vn: u32) -> Option<Reg> {
+    self.regs.iter().position(|v| *v == Some(vn)).map(|i| Reg(i as u8))
+  }
+
+  fn set(&mut self, r: Reg, vn: u32) {
+    if r != crate::riscv::ZERO {
+      self.regs[r.0 as usize] = Some(vn);
+    }
+  }
+
+  fn clobber(&mut self, set: RegSet) {
+    for r in set.iter() {
+      self.regs[r.0 as usize] = None;
+    }
+  }
+
+  fn forget_memory(&mut self) {
+    self.values.retain(|v, _| !v.is_memory());
+  }
+
+  fn reset(&mut self) {
+    self.values.clear();
+    self.regs = [None; 32];
+    // keep `next` going so stale numbers can never match new ones
+  }
+}
+
+/**
+ * Local value numbering. Within a straight line of code (from one label to
+ * the next) this tracks what value each register holds, so that recomputing
+ * something that is already in a register, or loading a variable again when
+ * nothing could have stored to it since, becomes a copy instead. Reads are
+ * also pointed at the first register holding their value, which usually
+ * leaves those copies dead for `dce` to remove.
+ */
+pub fn lvn(instrs: &mut Vec<Instr>) {
+  let mut table = ValueTable::default();
+  let mut src = std::mem::take(instrs);
+  let mut live = liveness(&src);
+  let mut out = Vec::with_capacity(src.len());
+
+  for i in 0..src.len() {
+    // if this would overwrite the only copy of something that gets loaded or
+    // materialised again further down, try writing somewhere else instead.
+    let single_def = src[i].is_pure() || matches!(src[i], Instr::LoadSym(..) | Instr::Load(..));
+    if let Some(rd) = src[i].defs().iter().next().filter(|_| single_def) {
+      let only_copy = table.regs[rd.0 as usize]
+        .filter(|vn| table.regs.iter().filter(|v| **v == Some(*vn)).count() == 1)
+        .and_then(|vn| table.values.iter().find(|(_, v)| **v == vn).map(|(k, _)| k.clone()));
+      if let Some(value) = only_copy {
+        if needed_again(&src[i + 1..], &value) {
+          rename_def(&mut src, i, rd, &mut live);
+        }
+      }
+    }
+
+    let mut instr = src[i].clone();
+    if let Instr::Label(_) = instr {
+      table.reset();
+      out.push(instr);
+      continue;
+    }
+
+    let uses: Vec<(Reg, u32)> = instr.uses().iter().map(|r| (r, table.of(r))).collect();
+    instr.map_uses(|u| {
+      let vn = uses.iter().find(|(r, _)| *r == u).map(|(_, vn)| *vn).unwrap();
+      table.holder(vn).unwrap_or(u)
+    });
+
+    let value = match &instr {
+      Instr::Li(_, imm) => Some(Value::Const(*imm)),
+      Instr::La(_, sym) => Some(Value::Addr(sym.clone())),
+      Instr::LoadSym(m, _, sym) => Some(Value::Sym(m, sym.clone())),
+      Instr::Load(m, _, off, base) => Some(Value::Mem(m, *off, table.of(*base))),
+      Instr::Op(m, _, a, b) => {
+        let (mut a, mut b) = (table.of(*a), table.of(*b));
+        if COMMUTATIVE.contains(m) && a > b {
+          std::mem::swap(&mut a, &mut b);
+        }
+        Some(Value::Op(m, a, b))
+      },
+      Instr::OpImm(m, _, a, imm) => Some(Value::OpImm(m, table.of(*a), *imm)),
+      Instr::Unary(m, _, a) => Some(Value::Unary(m, table.of(*a))),
+      _ => None,
+    };
+
+    match instr {
+      Instr::Mv(rd, rs) => {
+        let vn = table.of(rs);
+        if table.regs[rd.0 as usize] == Some(vn) {
+          continue; // already there
+        }
+        table.set(rd, vn);
+        out.push(instr);
+      },
+      Instr::StoreSym(m, rs, ref sym, tmp) => {
+        // a store to a variable can't change any other variable, but it might
+        // be what some pointer points at.
+        table.values.retain(|v, _| !matches!(v, Value::Mem(..)) && !matches!(v, Value::Sym(_, s) if s == sym));
+        if let Some(load) = reloads(m) {
+          let vn = table.of(rs);
+          table.values.insert(Value::Sym(load, sym.clone()), vn);
+        }
+        let clobbered = table.fresh();
+        table.set(tmp, clobbered);
+        out.push(instr);
+      },
+      Instr::Store(m, rs, off, base) => {
+        table.forget_memory();
+        if let Some(load) = reloads(m) {
+          let (vn, base) = (table.of(rs), table.of(base));
+          table.values.insert(Value::Mem(load, off, base), vn);
+        }
+        out.push(instr);
+      },
+      Instr::Call(..) | Instr::Ecall => {
+        table.forget_memory();
+        table.clobber(instr.defs());
+        out.push(instr);
+      },
+      _ => {
+        if let Some(value) = value {
+          let rd = instr.defs().iter().next().unwrap();
+          let existing = table.values.get(&value).copied();
+          match existing.and_then(|vn| table.holder(vn).map(|h| (vn, h))) {
+            Some((_, h)) if h == rd => continue, // already there
+            Some((vn, h)) => {
+              table.set(rd, vn);
+              out.push(Instr::Mv(rd, h));
+            },
+            None => {
+              let vn = table.number(value);
+              table.set(rd, vn);
+              out.push(instr);
+            },
+          }
+        } else {
+          table.clobber(instr.defs());
+          out.push(instr);
+        }
+      },
+    }
+  }
+
+  *instrs = out;
+}
+
+/**
+ * The load that gets back exactly what `store` wrote. Only whole words do,
+ * since a byte could be read back with or without its sign.
+ */
+fn reloads(store: &str) -> Option<&'static str> {
+  (store == "sw").then_some("lw")
+}
+
+/// true if `value` gets computed again before the end of the block.
+fn needed_again(rest: &[Instr], value: &Value) -> bool {
+  for instr in rest {
+    match (instr, value) {
+      (Instr::Label(_), _) => return false,
+      (Instr::Li(_, a), Value::Const(b)) if a == b => return true,
+      (Instr::La(_, a), Value::Addr(b)) if a == b => return true,
+      (Instr::LoadSym(m, _, a), Value::Sym(n, b)) if a == b && m == n => return true,
+      // the variable could change after these, so an earlier load is no good.
+      (Instr::StoreSym(_, _, a, _), Value::Sym(_, b)) if a == b => return false,
+      (Instr::Store(..) | Instr::Call(..) | Instr::Ecall, Value::Sym(..)) => return false,
+      _ => (),
+    }
+  }
+  false
+}
+
+/**
+ * Makes `src[i]` write to some other temporary register so that whatever is
+ * in its destination right now survives, renaming the reads that follow to
+ * match. Gives up (leaving everything as it was) if there is no temporary
+ * that's unused for the rest of the block, or if the new value would need to
+ * be in the old register somewhere else in the program.
+ */
+fn rename_def(src: &mut [Instr], i: usize, rd: Reg, live: &mut [RegSet]) -> bool {
+  const TEMPS: &[Reg] = &[T0, T1, T2, T3, T4, T5, T6];
+  if !TEMPS.contains(&rd) {
+    return false;
+  }
+  let end = src[i + 1..].iter().position(|instr| matches!(instr, Instr::Label(_)))
+    .map_or(src.len(), |p| i + 1 + p);
+
+  // find the reads of the value being renamed, and make sure nothing outside
+  // of this block expects it to be in rd.
+  let mut redefined = None;
+  let mut last_use = i;
+  for j in i + 1..end {
+    if src[j].uses().contains(rd) {
+      last_use = j;
+    }
+    if src[j].defs().contains(rd) {
+      redefined = Some(j);
+      break;
+    }
+    if src[j].ends_block() && live[j].contains(rd) {
+      return false;
+    }
+  }
+  if redefined.is_none() && live[last_use].contains(rd) {
+    return false;
+  }
+  let last = last_use;
+
+  // the new register has to hold the value from here until its last read.
+  let mentioned = mentioned_regs(&src[i..=last]);
+  let new = match TEMPS.iter().find(|r| !mentioned.contains(**r) && !live[i].contains(**r)) {
+    Some(r) => *r,
+    None => return false,
+  };
+
+  src[i].set_def(new);
+  for instr in &mut src[i + 1..=last] {
+    instr.map_uses(|u| if u == rd { new } else { u });
+  }
+  // rd only gets less live, so the old liveness is still safe for it, but
+  // the new register is live now where it wasn't before.
+  for l in &mut live[i..last] {
+    *l = l.with(new);
+  }
+  true
+}
+
+/**
+ * Dead code elimination: removes computations and loads whose result is
+ * never read. Changes to the stack pointer are always kept, since nothing
+ * here knows the caller expects it back where it was.
+ */
+pub fn dce(instrs: &mut Vec<Instr>) {
+  loop {
+    let live = liveness(instrs);
+    let before = instrs.len();
+    let mut i = 0;
+    instrs.retain(|instr| {
+      let removable = (instr.is_pure() || matches!(instr, Instr::LoadSym(..) | Instr::Load(..)))
+        && !instr.defs().contains(SP);
+      let dead = instr.defs().0 & live[i].0 == 0;
+      let self_move = matches!(instr, Instr::Mv(rd, rs) if rd == rs);
+      i += 1;
+      !(removable && dead || self_move)
+    });
+    if instrs.len() == before {
+      break;
+    }
+  }
+}
+
+/**
+ * Small cleanups on finished code: jumps to the label right after them, and
+ * code after a jump or return that no label leads to.
+ */
+pub fn peephole(instrs: &mut Vec<Instr>) {
+  let mut out: Vec<Instr> = Vec::with_capacity(instrs.len());
+  let mut reachable = true;
+  for instr in instrs.drain(..) {
+    if let Instr::Label(_) = instr {
+      reachable = true;
+    }
+    if reachable {
+      reachable = instr.falls_through();
+      out.push(instr);
+    }
+  }
+
+  let mut i = 0;
+  while i < out.len() {
+    let to_next = match &out[i] {
+      Instr::Jump(target) => out[i + 1..].iter()
+        .take_while(|n| matches!(n, Instr::Label(_)))
+        .any(|n| matches!(n, Instr::Label(l) if l == target)),
+      Instr::Mv(rd, rs) => rd == rs,
+      _ => false,
+    };
+    if to_next {
+      out.remove(i);
+    } else {
+      i += 1;
+    }
+  }
+  *instrs = out;
+}
+
+/**
+ * Replaces calls to standard library routines that just make a system call
+ * with the system call itself, which saves the jump there and back.
+ */
+pub fn inline_ecalls(instrs: &mut Vec<Instr>) {
+  let routines = crate::stdlib::routines();
+  let mut out = Vec::with_capacity(instrs.len());
+  for instr in instrs.drain(..) {
+    let ecall = match &instr {
+      Instr::Call(name, _) => routines.iter().find(|r| r.name == name).and_then(|r| r.ecall),
+      _ => None,
+    };
+    match ecall {
+      Some(n) => {
+        out.push(Instr::Li(A7, n));
+        out.push(Instr::Ecall);
+      },
+      None => out.push(instr),
+    }
+  }
+  *instrs = out;
+}
@@ -1,14 +1,24 @@
+use std::collections::VecDeque;
 
 use crate::expr::*;
-use logos::{Logos, Lexer};
+use crate::diag::{Source, Span, Spanned};
+use crate::intern::Symbol;
+use logos::Logos;
 
 
 #[derive(Debug, Logos, PartialEq)]
 pub enum Tok<'a> {
-  #[regex("(-?)[0-9]+", |lex| lex.slice().parse())]
+  /// `123`, `0xff`, `0b1010`, `0o17` (any of them with `_` in between
+  /// digits), or a character like `'a'`. A `-` in front is an operator.
+  #[regex("[0-9][0-9_]*", |lex| lex_int(lex.slice()))]
+  #[regex("0[xX][0-9a-fA-F_]+", |lex| lex_int(lex.slice()))]
+  #[regex("0[bB][01_]+", |lex| lex_int(lex.slice()))]
+  #[regex("0[oO][0-7_]+", |lex| lex_int(lex.slice()))]
+  #[regex(r"'([^'\\\n]|[\u{80}-\u{10FFFF}]|\\'|\\[^'\n]+)'", |lex| lex_char(lex.slice()))]
   Lit(i64),
 
-  #[regex(r"[a-zA-Z_][a-zA-Z_\-0-9]*", |lex| lex.slice())]
+  /// `-` is always an operator, so `a-1` and `a-b` are subtractions
+  #[regex(r"[a-zA-Z_][a-zA-Z_0-9]*", |lex| lex.slice())]
   Ident(&'a str),
 
   #[token("+")]
@@ -30,6 +40,9 @@ pub enum Tok<'a> {
 
   #[token("=")]
   Equals,
+  /// `+=`, `<<=` and so on
+  #[regex(r"(\+|-|\*|/|%|<<|>>_|>>|&|\||\^)=", |lex| compound_op(lex.slice()))]
+  OpAssign(BinOp),
   #[token(";")]
   Semicolon,
 
@@ -72,114 +85,555 @@ pub enum Tok<'a> {
   Else,
   #[token("while")]
   While,
+  #[token("for")]
+  For,
+  #[token("in")]
+  In,
+  #[token("loop")]
+  Loop,
+  #[token("break")]
+  Break,
+  #[token("continue")]
+  Continue,
+  /// `'name`, naming a loop
+  #[regex(r"'[a-zA-Z_][a-zA-Z_0-9]*", loop_label)]
+  LoopLabel(&'a str),
+  #[token("?")]
+  Question,
+  #[token("..")]
+  DotDot,
+  #[token("..=")]
+  DotDotEq,
+  #[token("match")]
+  Match,
+  #[token("=>")]
+  FatArrow,
+  #[token("->")]
+  Arrow,
+  #[token("as")]
+  As,
+  #[token(".")]
+  Dot,
+  #[token("struct")]
+  Struct,
+  #[token("const")]
+  Const,
+  #[token("enum")]
+  Enum,
+  #[token(":")]
+  Colon,
+  #[token("fn")]
+  Fn,
+  #[token("return")]
+  Return,
+
+  /// `#[name]` in front of a function
+  #[regex(r"#\[[a-zA-Z_]+\]", |lex| &lex.slice()[2..lex.slice().len()-1])]
+  Attr(&'a str),
 
   #[token(",")]
   Comma,
 
-  #[regex(r#""([^"\\]|\\t|\\r|\\n|\\")*""#, lex_str)
-  ]
-  String(&'a str),
+  /// the bytes of a string literal, with its escapes (the same ones as a
+  /// character has) already worked out. Anything that isn't ASCII is UTF-8.
+  #[regex(r#""([^"\\]|[\u{80}-\u{10FFFF}]|\\.)*""#, |lex| lex_str(lex.slice()))]
+  String(Vec<u8>),
 
-  // this lets us not need lookahead which and is a bit of a hack but makes life easier
+  // this used to be needed since the parser didn't do any lookahead. it's
+  // still accepted, but warned about.
   #[token("set")]
   Set,
 
-  #[regex(r"//.*[\n\r]", logos::skip)]
+  /// `/// text`, documenting the `let`, `fn` or `const` after it. Just the
+  /// text, without the slashes.
+  #[regex(r"///(([^/\n\r]|[\u{80}-\u{10FFFF}])([^\n\r]|[\u{80}-\u{10FFFF}])*)?", |lex| &lex.slice()[3..])]
+  DocComment(&'a str),
+
+  // `//` comments go up to the end of the line (or file). `////` and more is
+  // a plain comment too, so that lines of slashes aren't documentation.
+  #[token("//", logos::skip)]
+  #[regex(r"//([^/\n\r]|[\u{80}-\u{10FFFF}])([^\n\r]|[\u{80}-\u{10FFFF}])*", logos::skip)]
+  #[regex(r"////([^\n\r]|[\u{80}-\u{10FFFF}])*", logos::skip)]
+  #[token("/*", block_comment)]
   #[regex(r"[ \t\n\r\f]+", logos::skip)]
+  // a number with a prefix and digits that don't go with it, which would
+  // otherwise lex as `0b10` and `2`, or `0` and the name `x`. The real
+  // literals win over this when they're the same length.
+  #[regex("0[xXbBoO][0-9a-zA-Z_]*", priority = 1)]
   #[error]
   Error,
 }
 
-fn lex_str<'a>(lex: &mut Lexer<'a, Tok<'a>>) -> Option<&'a str> {
-  Some(&lex.slice()[1..lex.slice().len()-1])
+/// the name of a loop label, unless a `'` comes right after it, in which case
+/// it was meant to be a character like `'ab'` and the whole thing is an error
+fn loop_label<'a>(lex: &mut logos::Lexer<'a, Tok<'a>>) -> Option<&'a str> {
+  if lex.remainder().starts_with('\'') {
+    lex.bump(1);
+    return None;
+  }
+  Some(&lex.slice()[1..])
+}
+
+/// skips a `/* */` comment, which can have other ones inside of it. One
+/// that's never closed is an error.
+fn block_comment<'a>(lex: &mut logos::Lexer<'a, Tok<'a>>) -> logos::FilterResult<()> {
+  let mut depth = 1;
+  let rest = lex.remainder().as_bytes();
+  let mut i = 0;
+  while i < rest.len() {
+    match &rest[i..(i + 2).min(rest.len())] {
+      b"/*" => {
+        depth += 1;
+        i += 2;
+      },
+      b"*/" => {
+        depth -= 1;
+        i += 2;
+        if depth == 0 {
+          lex.bump(i);
+          return logos::FilterResult::Skip;
+        }
+      },
+      _ => i += 1,
+    }
+  }
+  lex.bump(rest.len());
+  logos::FilterResult::Error
+}
+
+/// the operator in a compound assignment like `+=`
+fn compound_op(s: &str) -> Option<BinOp> {
+  Some(match &s[..s.len() - 1] {
+    "+" => BinOp::Add,
+    "-" => BinOp::Sub,
+    "*" => BinOp::Mul,
+    "/" => BinOp::Div,
+    "%" => BinOp::Rem,
+    "<<" => BinOp::Sll,
+    ">>_" => BinOp::Srl,
+    ">>" => BinOp::Sra,
+    "&" => BinOp::And,
+    "|" => BinOp::Or,
+    "^" => BinOp::Xor,
+    _ => return None,
+  })
+}
+
+/**
+ * The value of an integer literal, without its `_`s. Anything too big for
+ * 64 bits comes out as `i64::MAX`, so that the parser says it's too big
+ * rather than it not being a number at all.
+ */
+fn lex_int(s: &str) -> Option<i64> {
+  let (digits, radix) = match s.get(..2) {
+    Some("0x" | "0X") => (&s[2..], 16),
+    Some("0b" | "0B") => (&s[2..], 2),
+    Some("0o" | "0O") => (&s[2..], 8),
+    _ => (s, 10),
+  };
+  let digits: String = digits.chars().filter(|&c| c != '_').collect();
+  if digits.is_empty() {
+    return None;
+  }
+  Some(u64::from_str_radix(&digits, radix).map_or(i64::MAX, |v| v.min(i64::MAX as u64) as i64))
+}
+
+/// `'a'` or `'\n'`, as the number of the character
+fn lex_char(s: &str) -> Option<i64> {
+  let mut chars = s[1..s.len() - 1].chars();
+  let c = match chars.next()? {
+    '\\' => match escape(&mut chars)? {
+      Escaped::Byte(b) => b as i64,
+      Escaped::Char(c) => c as i64,
+    },
+    c => c as i64,
+  };
+  chars.next().is_none().then_some(c)
+}
+
+/// what a `\` and the characters after it stand for
+enum Escaped {
+  /// from `\x`, which is always a single byte, even above 0x7f
+  Byte(u8),
+  Char(char),
+}
+
+/// reads an escape from `chars`, which are right after a `\`: `\n`, `\t`,
+/// `\r`, `\0`, `\\`, `\'`, `\"`, `\x41` or `\u{e9}`
+fn escape(chars: &mut std::str::Chars) -> Option<Escaped> {
+  Some(Escaped::Char(match chars.next()? {
+    'n' => '\n',
+    't' => '\t',
+    'r' => '\r',
+    '0' => '\0',
+    c @ ('\\' | '\'' | '"') => c,
+    'x' => {
+      let hex: String = chars.take(2).collect();
+      return u8::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 2).map(Escaped::Byte);
+    },
+    'u' => {
+      if chars.next()? != '{' {
+        return None;
+      }
+      let hex: String = chars.by_ref().take_while(|&c| c != '}').collect();
+      if hex.is_empty() || hex.len() > 6 {
+        return None;
+      }
+      char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+    },
+    _ => return None,
+  }))
+}
+
+/// the bytes of a string literal, or None if it has an escape that doesn't exist
+fn lex_str(s: &str) -> Option<Vec<u8>> {
+  let mut bytes = vec![];
+  let mut chars = s[1..s.len() - 1].chars();
+  while let Some(c) = chars.next() {
+    let c = match c {
+      '\\' => match escape(&mut chars)? {
+        Escaped::Byte(b) => {
+          bytes.push(b);
+          continue;
+        },
+        Escaped::Char(c) => c,
+      },
+      c => c,
+    };
+    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+  }
+  Some(bytes)
+}
+
+/**
+ * What to do about a piece of the source that isn't a token: it gets reported
+ * (once, where it is), and then either dropped or, for a literal that's
+ * there but broken, swapped for one that works so the parser doesn't trip
+ * over it too.
+ */
+fn lex_error<'a>(diag: &Source, text: &str, span: Span) -> Option<Tok<'a>> {
+  let closed = |quote| text.len() > 1 && text.starts_with(quote) && text.ends_with(quote);
+  if text.starts_with("/*") {
+    diag.error(span, "block comment is never closed");
+    None
+  } else if closed('"') {
+    diag.error(span, format!("{} has an escape that doesn't exist", text));
+    Some(Tok::String(vec![]))
+  } else if text.starts_with('"') {
+    diag.error(span, "string is never closed");
+    Some(Tok::String(vec![]))
+  } else if let Some(kind) = radix(text) {
+    diag.error(span, format!("{} isn't {} number", text, kind));
+    Some(Tok::Lit(0))
+  } else if closed('\'') {
+    diag.error(span, format!("{} isn't a character (or has an escape that doesn't exist)", text));
+    Some(Tok::Lit(0))
+  } else {
+    diag.error(span, format!("unrecognized token {}", text));
+    None
+  }
+}
+
+/// what kind of number a literal with a `0x`, `0b` or `0o` prefix is
+fn radix(text: &str) -> Option<&'static str> {
+  match text.get(..2)? {
+    "0x" | "0X" => Some("a hexadecimal"),
+    "0b" | "0B" => Some("a binary"),
+    "0o" | "0O" => Some("an octal"),
+    _ => None,
+  }
 }
 
 struct Lex<'a> {
-  tokens: Vec<Tok<'a>>
+  src: &'a str,
+  diag: Source<'a>,
+  tokens: std::iter::Peekable<logos::SpannedIter<'a, Tok<'a>>>,
+  /// tokens that have been lexed but not popped yet, next one first. There's
+  /// always at least one, unless the file is over.
+  ahead: VecDeque<(Tok<'a>, Span)>,
+  /// how many tokens have been taken, so the parser can tell if a statement
+  /// got anywhere
+  taken: usize,
+  /// where the last token that was popped is
+  last: Span,
+  /// and the one before it, for when the last one gets put back
+  before_last: Span,
+  /// how many `{` have been popped and not closed yet
+  depth: usize,
+  /// how many errors there have been, the lexer's included
+  errors: usize,
+  /// set by a syntax error until the next statement starts, so that the
+  /// errors that only happen because of the first one aren't reported
+  recovering: bool,
+  /// set when a string or comment that's never closed took the rest of the
+  /// file, so that whatever it left unfinished isn't reported as well
+  swallowed: bool,
 }
 
 impl<'a> Lex<'a> {
   fn new(input: &'a str) -> Self {
-    let l = Tok::lexer(input);
-    let mut v = Vec::from_iter(l.into_iter());
-    v.reverse();
-    Self {
-      tokens: v
+    let mut lex = Self {
+      src: input,
+      diag: Source::new(input),
+      tokens: Tok::lexer(input).spanned().peekable(),
+      ahead: VecDeque::new(),
+      taken: 0,
+      last: Span::default(),
+      before_last: Span::default(),
+      depth: 0,
+      errors: 0,
+      recovering: false,
+      swallowed: false,
+    };
+    lex.fill(0);
+    lex
+  }
+
+  /// lexes until token `n` after the next one is there (or the file ends).
+  /// Lexer errors get reported here, when they're reached.
+  fn fill(&mut self, n: usize) {
+    while self.ahead.len() <= n {
+      let (t, r) = match self.tokens.next() {
+        Some(next) => next,
+        None => return,
+      };
+      let mut span = Span::new(r.start, r.end);
+      if t != Tok::Error {
+        self.ahead.push_back((t, span));
+        continue;
+      }
+      // so that `$$` is one error instead of two
+      while let Some((Tok::Error, r)) = self.tokens.peek() {
+        if r.start != span.end {
+          break;
+        }
+        span.end = r.end;
+        self.tokens.next();
+      }
+      self.errors += 1;
+      let text = &self.src[span.start..span.end];
+      self.swallowed = span.end == self.src.len() && (text.starts_with('"') || text.starts_with("/*"));
+      if let Some(t) = lex_error(&self.diag, text, span) {
+        self.ahead.push_back((t, span));
+      }
+    }
+  }
+
+  /// a syntax error at the next token. Nothing is reported while recovering
+  /// from another one.
+  fn error(&mut self, message: impl std::fmt::Display) {
+    let span = self.ahead.front().map_or(Span::new(self.last.end, self.last.end), |(_, span)| *span);
+    self.syntax_error(span, message);
+  }
+
+  /// a syntax error about something missing after the last token. It's
+  /// pointed at the next one, unless that's on a later line, like when a
+  /// `;` is missing at the end of a line.
+  fn missing(&mut self, message: impl std::fmt::Display) {
+    let span = match self.ahead.front() {
+      Some((_, span)) if self.last.end == 0 || !self.src[self.last.end..span.start].contains('\n') => *span,
+      _ => Span::new(self.last.end, self.last.end),
+    };
+    self.syntax_error(span, message);
+  }
+
+  fn syntax_error(&mut self, span: Span, message: impl std::fmt::Display) {
+    if self.recovering || (self.swallowed && self.ahead.is_empty()) {
+      return;
+    }
+    self.diag.error(span, message);
+    self.errors += 1;
+    self.recovering = true;
+  }
+
+  /// an error about the token that was just popped, which the parser can
+  /// carry on after
+  fn error_last(&mut self, message: impl std::fmt::Display) {
+    self.diag.error(self.last, message);
+    self.errors += 1;
+  }
+
+  /// the next token the way it's written, for errors
+  fn next_text(&self) -> String {
+    match self.ahead.front() {
+      Some((_, span)) => format!("`{}`", &self.src[span.start..span.end]),
+      None => "the end of the file".to_string(),
     }
   }
 
   fn pop(&mut self) -> Option<Tok<'a>> {
-    let ret = 
-        match self.tokens.pop() {
-        Some(Tok::Error) => {
-          eprintln!("ERR: unrecognized token");
-          self.pop()
-        },
-        other => other,
-      }
-    ;
-    ret
+    self.skip_docs();
+    let (t, span) = self.ahead.pop_front()?;
+    self.fill(0);
+    self.taken += 1;
+    self.before_last = self.last;
+    self.last = span;
+    match t {
+      Tok::LBracket => self.depth += 1,
+      Tok::RBracket => self.depth = self.depth.saturating_sub(1),
+      _ => (),
+    }
+    Some(t)
   }
 
   fn peek(&mut self) -> Option<&Tok<'a>> {
-    match self.tokens.last() {
-      Some(Tok::Error) => {
-        panic!("ERR: unrecognized token: {:?}", self.tokens.last().unwrap());
-      },
-      other => other,
+    self.skip_docs();
+    self.ahead.front().map(|(t, _)| t)
+  }
+
+  /// the lines of the `///` comments that are next, if there are any
+  fn take_doc(&mut self) -> Vec<String> {
+    let mut doc = vec![];
+    while let Some((Tok::DocComment(line), _)) = self.ahead.front() {
+      doc.push(line.strip_prefix(' ').unwrap_or(line).trim_end().to_string());
+      self.ahead.pop_front();
+      self.fill(0);
+      self.taken += 1;
     }
+    doc
   }
 
+  /// `///` comments in the middle of a statement don't go with anything
+  fn skip_docs(&mut self) {
+    if let Some(&(Tok::DocComment(_), span)) = self.ahead.front() {
+      self.take_doc();
+      self.diag.warn(span, "doc comment in the middle of a statement is ignored");
+    }
+  }
+
+  /// the token `n` after the next one
+  fn peek_nth(&mut self, n: usize) -> Option<&Tok<'a>> {
+    self.fill(n);
+    self.ahead.get(n).map(|(t, _)| t)
+  }
+
+  /// puts back the token that was just popped
   fn push(&mut self, t: Tok<'a>) {
-    self.tokens.push(t)
+    match t {
+      Tok::LBracket => self.depth -= 1,
+      Tok::RBracket => self.depth += 1,
+      _ => (),
+    }
+    self.ahead.push_front((t, self.last));
+    self.taken -= 1;
+    self.last = self.before_last;
+  }
+
+  /// the text of the last token that was popped
+  fn text(&self) -> &'a str {
+    &self.src[self.last.start..self.last.end]
+  }
+
+  /// where the next token starts
+  fn pos(&self) -> usize {
+    self.ahead.front().map_or(self.last.end, |(_, span)| span.start)
+  }
+
+  /// from `start` up to the end of the last token popped
+  fn span_from(&self, start: usize) -> Span {
+    Span::new(start, self.last.end.max(start))
   }
 }
 
-pub fn parse(input: &str) -> Option<Block> {
+/**
+ * The program, and how many errors it has. A statement with a syntax error
+ * is left in as a `Stmt::Error`, and parsing carries on after it.
+ */
+pub fn parse(input: &str) -> (Block, usize) {
   let mut lex = Lex::new(input);
 
   // useful for lexer debugging:
-  // for t in lex.tokens.iter().rev() {
+  // for t in Tok::lexer(input).spanned() {
   //   println!("{:?}", t);
   // }
 
-  parse_block(&mut lex)
+  let mut block = parse_block(&mut lex);
+  // a block only stops at a } or the end, and this one isn't in anything
+  while match_tok(&mut lex, Tok::RBracket).is_some() {
+    lex.error_last("unmatched }");
+    block.extend(parse_block(&mut lex));
+  }
+  (block, lex.errors)
 }
 
-fn parse_block<'a>(lex: &mut Lex) -> Option<Block> {
+/// every kind of statement, in the order they're tried. Only expressions
+/// don't start with a keyword, so they go last.
+const STATEMENTS: [fn(&mut Lex) -> Option<Stmt>; 12] = [
+  parse_decl,
+  parse_assign,
+  parse_if_stmt,
+  parse_loop,
+  parse_match,
+  parse_break,
+  parse_fn,
+  parse_struct,
+  parse_const,
+  parse_enum,
+  parse_return,
+  parse_expr_stmt,
+];
+
+/// statements up to a `}` or the end of the file, whichever is first
+fn parse_block(lex: &mut Lex) -> Block {
   let mut ret = vec![];
 
-  loop { // todo: something more fault tolerant?
-    if let Some(s) = parse_decl(lex) {
-      ret.push(s);
-      continue;
-    } else if let Some(s) = parse_assign(lex) {
-      ret.push(s);
-      continue;
-    } else if let Some(s) = parse_expr_stmt(lex) {
-      ret.push(s);
-      continue;
-    } else if let Some(s) = parse_if_stmt(lex) {
-      ret.push(s);
-      continue;
-    } else if let Some(s) = parse_while(lex) {
-      ret.push(s);
-      continue;
-    } else {
+  loop {
+    let doc = lex.take_doc();
+    if matches!(lex.peek(), None | Some(Tok::RBracket)) {
+      if !doc.is_empty() {
+        let span = Span::new(lex.pos(), lex.pos());
+        lex.diag.warn(span, "doc comment at the end of a block doesn't document anything");
+      }
       break;
     }
+
+    lex.recovering = false;
+    let start = lex.pos();
+    let taken = lex.taken;
+    let depth = lex.depth;
+    let mut s = None;
+    for parse in STATEMENTS {
+      s = parse(lex);
+      // a statement that went wrong after its first token isn't anything else
+      if s.is_some() || lex.taken != taken {
+        break;
+      }
+    }
+    let s = s.unwrap_or_else(|| {
+      // unless the statement got to its end before going wrong, skip
+      // the rest of it
+      let open = lex.depth - depth;
+      if open > 0 || lex.taken == taken || !matches!(lex.text(), ";" | "}") {
+        synchronize(lex, open);
+      }
+      Stmt::Error
+    });
+
+    let mut s = Spanned::new(s, lex.span_from(start));
+    if !doc.is_empty() {
+      if matches!(s.node, Stmt::Decl(..) | Stmt::Fn(_) | Stmt::Const(..)) {
+        s.doc = doc;
+      } else if !matches!(s.node, Stmt::Error) {
+        lex.diag.warn(s.span, "doc comments only go before a let, fn or const");
+      }
+    }
+    ret.push(s);
   }
 
-  if ret.len() >= 1 {
-    Some(ret)
-  } else {
-    None
+  ret
+}
+
+/// names starting with `__` are left for the compiler, e.g. the inliner
+/// renames variables to `__inline_<n>_<name>`.
+fn check_name(lex: &mut Lex, name: &str) {
+  if name.starts_with("__") {
+    lex.error_last(format!("{} is a reserved name (names can't start with __)", name));
   }
 }
 
-fn parse_if_stmt<'a>(lex: &mut Lex) -> Option<Stmt> {
+fn parse_if_stmt(lex: &mut Lex) -> Option<Stmt> {
   match lex.peek() {
-    Some(Tok::If) => {lex.pop(); ()},
+    Some(Tok::If) => {lex.pop();},
     _ => {
       return None
     },
@@ -187,200 +641,558 @@ fn parse_if_stmt<'a>(lex: &mut Lex) -> Option<Stmt> {
   let mut ok = true;
   let condition = parse_expr(lex);
 
-  expect(lex, Tok::LBracket, "ERR: expected { after if")?;
+  expect(lex, Tok::LBracket, "expected { after if")?;
 
   let true_block = parse_block(lex);
-  ok = if let Some(_) = expect(lex, Tok::RBracket, "ERR: expected } after if 'true' block")
+  ok = if expect(lex, Tok::RBracket, "expected } after if 'true' block").is_some()
     { ok } else { false };
 
-  let else_block = if let Some(_) = match_tok(lex, Tok::Else) {
-    expect(lex, Tok::LBracket, "ERR: expected { after else")?;
+  let else_block = if match_tok(lex, Tok::Else).is_some() {
+    expect(lex, Tok::LBracket, "expected { after else")?;
     let b = parse_block(lex);
-    expect(lex, Tok::RBracket, "ERR: expected } after else block")
-      .map(|_| b.unwrap())
+    expect(lex, Tok::RBracket, "expected } after else block")
+      .map(|_| b)
   } else { None };
 
   if ok {
-    Some(Stmt::If(condition?, true_block?, else_block))
+    Some(Stmt::If(condition?, true_block, else_block))
   } else {
     None
   }
 }
 
-fn parse_while<'a>(lex: &mut Lex) -> Option<Stmt> {
-  match lex.peek() {
-    Some(Tok::While) => {lex.pop(); ()},
+/// `while`, `loop` and both kinds of `for`, with an optional `'label:` in front
+fn parse_loop(lex: &mut Lex) -> Option<Stmt> {
+  if !matches!(lex.peek(), Some(Tok::While | Tok::Loop | Tok::For | Tok::LoopLabel(_))) {
+    return None;
+  }
+  let start = lex.pos();
+  let label = match match_tok(lex, Tok::LoopLabel("" as _)) {
+    Some(Tok::LoopLabel(l)) => {
+      expect(lex, Tok::Colon, "expected : after loop label")?;
+      Some(l.into())
+    },
+    _ => None,
+  };
+  let mut l = Loop { label, init: vec![], cond: None, body: vec![], step: vec![] };
+
+  match lex.pop() {
+    Some(Tok::While) => {
+      l.cond = Some(parse_expr(lex)?);
+    },
+    Some(Tok::Loop) => (),
+    Some(Tok::For) if matches!(lex.peek_nth(1), Some(Tok::In)) => {
+      // for i in a..b is for let i = a; i < b; i += 1, except b is only
+      // worked out once.
+      let name = match lex.pop() {
+        Some(Tok::Ident(name)) => Symbol::intern(name),
+        _ => {
+          lex.error("expected loop variable after for");
+          return None;
+        },
+      };
+      check_name(lex, name.as_str());
+      expect(lex, Tok::In, "expected in after for loop variable")?;
+      let from = parse_expr(lex)?;
+      expect(lex, Tok::DotDot, "expected .. in for loop range")?;
+      let end = parse_expr(lex)?;
+      let var = || Box::new(Expr::Ident(name));
+      let span = lex.span_from(start);
+      l.init = vec![
+        Spanned::new(Stmt::Decl(name, None, from), span),
+        Spanned::new(Stmt::Decl("__end".into(), None, end), span),
+      ];
+      l.cond = Some(Expr::Bin(var(), BinOp::Less, Box::new(Expr::Ident("__end".into()))));
+      let step = Stmt::Assignment(name, Expr::Bin(var(), BinOp::Add, Box::new(Expr::Lit(1))));
+      l.step = vec![Spanned::new(step, span)];
+    },
+    Some(Tok::For) => {
+      // for init; cond; step, where any of the three can be left out
+      if match_tok(lex, Tok::Semicolon).is_none() {
+        let init_start = lex.pos();
+        let init = match parse_decl(lex) {
+          Some(s) => s,
+          None => parse_expr_stmt(lex)?,
+        };
+        l.init.push(Spanned::new(init, lex.span_from(init_start)));
+      }
+      if match_tok(lex, Tok::Semicolon).is_none() {
+        l.cond = Some(parse_expr(lex)?);
+        expect(lex, Tok::Semicolon, "expected ; after for loop condition")?;
+      }
+      if !matches!(lex.peek(), Some(Tok::LBracket)) {
+        let step_start = lex.pos();
+        let step = parse_simple_stmt(lex)?;
+        l.step.push(Spanned::new(step, lex.span_from(step_start)));
+      }
+    },
+    // only after a label, since otherwise this was checked up above
     _ => {
-      return None
+      lex.error("expected a loop after loop label");
+      return None;
     },
   }
-  let condition = parse_expr(lex);
 
-  expect(lex, Tok::LBracket, "ERR: expected { after while")?;
+  expect(lex, Tok::LBracket, "expected { before loop body")?;
+  l.body = parse_block(lex);
+  expect(lex, Tok::RBracket, "expected } after loop body")?;
 
-  let true_block = parse_block(lex);
-  expect(lex, Tok::RBracket, "ERR: expected } after while body")?;
+  Some(Stmt::Loop(Box::new(l)))
+}
 
-  Some(Stmt::While(condition?, true_block?))
+/// `match value { 1 => { ... }, 2 | 3 => { ... }, 4..=9 => { ... }, _ => { ... } }`
+fn parse_match(lex: &mut Lex) -> Option<Stmt> {
+  match_tok(lex, Tok::Match)?;
+  let value = parse_expr(lex)?;
+  expect(lex, Tok::LBracket, "expected { after match value")?;
+
+  let mut arms = vec![];
+  while match_tok(lex, Tok::RBracket).is_none() {
+    let mut patterns = vec![parse_pattern(lex)?];
+    while match_tok(lex, Tok::Or).is_some() {
+      patterns.push(parse_pattern(lex)?);
+    }
+    expect(lex, Tok::FatArrow, "expected => after match pattern")?;
+    expect(lex, Tok::LBracket, "expected { after =>")?;
+    let body = parse_block(lex);
+    expect(lex, Tok::RBracket, "expected } after match arm")?;
+    match_tok(lex, Tok::Comma); // optional
+    arms.push(Arm { patterns, body });
+  }
+  Some(Stmt::Match(value, arms))
 }
 
-fn parse_decl<'a>(lex: &mut Lex) -> Option<Stmt> {
-  match_tok(lex, Tok::Let)?;
-  let name = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
+fn parse_pattern(lex: &mut Lex) -> Option<Pattern> {
+  match lex.peek() {
+    Some(Tok::Ident("_")) => {
+      lex.pop();
+      return Some(Pattern::Wildcard);
+    },
+    Some(Tok::Ident(name)) => {
+      let name = Symbol::intern(name);
+      lex.pop();
+      return Some(Pattern::Const(name));
+    },
+    _ => (),
+  }
+  let low = match parse_pattern_number(lex) {
+    Some(low) => low,
+    None => {
+      lex.error(format!("expected a number, a range, a constant or _ as a match pattern, found {}", lex.next_text()));
+      return None;
+    },
+  };
+  if match_tok(lex, Tok::DotDotEq).is_none() {
+    return Some(Pattern::Value(low));
+  }
+  match parse_pattern_number(lex) {
+    Some(high) if high >= low => Some(Pattern::Range(low, high)),
+    Some(high) => {
+      lex.error_last(format!("empty range {}..={} in match pattern", low, high));
+      None
+    },
+    None => {
+      lex.error("expected a number after ..= in match pattern");
+      None
+    },
+  }
+}
+
+/// a literal in a pattern, which can have a `-` in front
+fn parse_pattern_number(lex: &mut Lex) -> Option<i64> {
+  let negative = match_tok(lex, Tok::Minus).is_some();
+  match match_tok(lex, Tok::Lit(0)) {
+    Some(Tok::Lit(v)) => Some(check_lit(if negative { -v } else { v }, lex)),
+    _ => None,
+  }
+}
+
+/// `break` or `continue`, either of which can name the loop it's for
+fn parse_break(lex: &mut Lex) -> Option<Stmt> {
+  let is_break = match lex.peek() {
+    Some(Tok::Break) => true,
+    Some(Tok::Continue) => false,
+    _ => return None,
+  };
+  lex.pop();
+  let label = match match_tok(lex, Tok::LoopLabel("" as _)) {
+    Some(Tok::LoopLabel(l)) => Some(l.into()),
+    _ => None,
+  };
+  if match_tok(lex, Tok::Semicolon).is_none() {
+    lex.missing(format!("semicolon expected after {}", if is_break { "break" } else { "continue" }));
+    return None;
+  }
+  Some(if is_break { Stmt::Break(label) } else { Stmt::Continue(label) })
+}
+
+fn parse_fn(lex: &mut Lex) -> Option<Stmt> {
+  let inline = match lex.peek() {
+    Some(Tok::Fn) => InlineHint::Default,
+    Some(&Tok::Attr(attr)) => {
+      lex.pop();
+      match attr {
+        "inline" => InlineHint::Always,
+        "noinline" => InlineHint::Never,
+        other => {
+          lex.error_last(format!("unknown attribute #[{}]", other));
+          InlineHint::Default
+        },
+      }
+    },
+    _ => return None,
+  };
+  expect(lex, Tok::Fn, "expected fn after attribute")?;
+
+  let name: Symbol = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
+    check_name(lex, name);
     name.into()
   } else {
-    eprintln!("ERR: identifier expected in declaration");
-    synchronize(lex);
+    lex.error("expected function name after fn");
     return None;
   };
 
-  // parse assignment, or fill in 0 otherwise
-  let val = if let Some(_) = match_tok(lex, Tok::Equals) {
-    match lex.pop() {
-      Some(Tok::Lit(val)) => {
-        DeclInit::Int(val)
+  expect(lex, Tok::LParen, "expected ( after function name")?;
+  let mut params = vec![];
+  let mut param_types = vec![];
+  if match_tok(lex, Tok::RParen).is_none() {
+    loop {
+      match match_tok(lex, Tok::Ident("" as _)) {
+        Some(Tok::Ident(p)) => {
+          check_name(lex, p);
+          params.push(p.into());
+          param_types.push(match match_tok(lex, Tok::Colon) {
+            Some(_) => parse_type(lex)?,
+            None => Type::I32,
+          });
+        },
+        _ => {
+          lex.error(format!("expected parameter name in definition of {}", name));
+          return None;
+        },
+      }
+      if match_tok(lex, Tok::RParen).is_some() {
+        break;
+      }
+      expect(lex, Tok::Comma, "expected comma between parameters")?;
+    }
+  }
+
+  let ret = match match_tok(lex, Tok::Arrow) {
+    Some(_) => parse_type(lex)?,
+    None => Type::I32,
+  };
+
+  expect(lex, Tok::LBracket, "expected { after function parameters")?;
+  let body = parse_block(lex);
+  expect(lex, Tok::RBracket, "expected } after function body")?;
+
+  Some(Stmt::Fn(FnDecl { name, params, param_types, ret, body, inline }))
+}
+
+/// `struct Name { field: type, ... }`, with an optional comma at the end
+fn parse_struct(lex: &mut Lex) -> Option<Stmt> {
+  match_tok(lex, Tok::Struct)?;
+  let name: Symbol = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
+    check_name(lex, name);
+    name.into()
+  } else {
+    lex.error("expected struct name after struct");
+    return None;
+  };
+
+  expect(lex, Tok::LBracket, "expected { after struct name")?;
+  let mut fields = vec![];
+  while match_tok(lex, Tok::RBracket).is_none() {
+    let field = match match_tok(lex, Tok::Ident("" as _)) {
+      Some(Tok::Ident(field)) => field,
+      _ => {
+        lex.error(format!("expected field name in definition of {}", name));
+        return None;
       },
-      Some(Tok::String(s)) => {
-        DeclInit::Str(s.into())
+    };
+    expect(lex, Tok::Colon, "expected : after field name")?;
+    fields.push((field.into(), parse_type(lex)?));
+    if match_tok(lex, Tok::Comma).is_none() {
+      expect(lex, Tok::RBracket, "expected comma between fields")?;
+      break;
+    }
+  }
+
+  Some(Stmt::Struct(StructDecl { name, fields, size: 0, align: 0 }))
+}
+
+/// `const NAME = value;`
+fn parse_const(lex: &mut Lex) -> Option<Stmt> {
+  match_tok(lex, Tok::Const)?;
+  let name: Symbol = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
+    check_name(lex, name);
+    name.into()
+  } else {
+    lex.error("expected a name after const");
+    return None;
+  };
+  if match_tok(lex, Tok::Equals).is_none() {
+    lex.error(format!("const {} needs a value", name));
+    return None;
+  }
+  let value = parse_expr(lex)?;
+  if match_tok(lex, Tok::Semicolon).is_none() {
+    lex.missing("semicolon expected after const");
+    return None;
+  }
+  Some(Stmt::Const(name, value))
+}
+
+/// `enum Name { A, B = value, ... }`, with an optional comma at the end
+fn parse_enum(lex: &mut Lex) -> Option<Stmt> {
+  match_tok(lex, Tok::Enum)?;
+  let name: Symbol = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
+    name.into()
+  } else {
+    lex.error("expected enum name after enum");
+    return None;
+  };
+
+  expect(lex, Tok::LBracket, "expected { after enum name")?;
+  let mut variants = vec![];
+  while match_tok(lex, Tok::RBracket).is_none() {
+    let variant = match match_tok(lex, Tok::Ident("" as _)) {
+      Some(Tok::Ident(variant)) => {
+        check_name(lex, variant);
+        variant
       },
       _ => {
-        eprintln!("ERR: value expected in declaration (note: expressions are not supported here)");
-        synchronize(lex);
+        lex.error(format!("expected variant name in definition of {}", name));
         return None;
-      }
+      },
+    };
+    let value = match match_tok(lex, Tok::Equals) {
+      Some(_) => Some(parse_expr(lex)?),
+      None => None,
+    };
+    variants.push((variant.into(), value));
+    if match_tok(lex, Tok::Comma).is_none() {
+      expect(lex, Tok::RBracket, "expected comma between variants")?;
+      break;
     }
-  } else { DeclInit::Int(0) };
+  }
+
+  Some(Stmt::Enum(name, variants))
+}
+
+fn parse_return(lex: &mut Lex) -> Option<Stmt> {
+  match_tok(lex, Tok::Return)?;
+  if match_tok(lex, Tok::Semicolon).is_some() {
+    return Some(Stmt::Return(None));
+  }
 
-  if let Some(_) = match_tok(lex, Tok::Semicolon) {
-    Some(Stmt::Decl(name, val))
+  let val = parse_expr(lex)?;
+
+  if match_tok(lex, Tok::Semicolon).is_some() {
+    Some(Stmt::Return(Some(val)))
   } else {
-    eprintln!("ERR: semicolon expected after declaration");
-    synchronize(lex);
+    lex.missing("semicolon expected after return");
     None
   }
 }
 
+fn parse_decl(lex: &mut Lex) -> Option<Stmt> {
+  match_tok(lex, Tok::Let)?;
+  let name = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
+    check_name(lex, name);
+    name.into()
+  } else {
+    lex.error("identifier expected in declaration");
+    return None;
+  };
+
+  let ty = match match_tok(lex, Tok::Colon) {
+    Some(_) => Some(parse_type(lex)?),
+    None => None,
+  };
+
+  // parse the initial value, or fill in 0 otherwise
+  let val = if match_tok(lex, Tok::Equals).is_some() {
+    parse_expr(lex)?
+  } else { Expr::Lit(0) };
+
+  if match_tok(lex, Tok::Semicolon).is_some() {
+    Some(Stmt::Decl(name, ty, val))
+  } else {
+    lex.missing("semicolon expected after declaration");
+    None
+  }
+}
+
+/// an integer type, `bool`, `str`, `*type` or the name of a struct
+fn parse_type(lex: &mut Lex) -> Option<Type> {
+  match lex.pop() {
+    Some(Tok::Star) => Some(Type::ptr(parse_type(lex)?)),
+    Some(Tok::Ident("i8")) => Some(Type::I8),
+    Some(Tok::Ident("u8")) => Some(Type::U8),
+    Some(Tok::Ident("i16")) => Some(Type::I16),
+    Some(Tok::Ident("u16")) => Some(Type::U16),
+    Some(Tok::Ident("i32")) => Some(Type::I32),
+    Some(Tok::Ident("u32")) => Some(Type::U32),
+    Some(Tok::Ident("bool")) => Some(Type::Bool),
+    Some(Tok::Ident("str")) => Some(Type::Str),
+    // the type checker finds out if there is such a struct
+    Some(Tok::Ident(other)) => Some(Type::Struct(other.into())),
+    other => {
+      if let Some(tok) = other { lex.push(tok) }
+      lex.error(format!("expected a type, found {}", lex.next_text()));
+      None
+    },
+  }
+}
+
 // todo: put this into impl Lex
 fn match_tok<'a>(lex: &mut Lex<'a>, expected: Tok) -> Option<Tok<'a>> {
-  if let Some(t) = lex.peek() {
-    match (t, expected) {
-      // this is stupid but fine for now (ie works)
-      (Tok::Lit(_), Tok::Lit(_)) => Some(lex.pop()?),
-      (Tok::Ident(_), Tok::Ident(_)) => Some(lex.pop()?),
-      (Tok::Plus, Tok::Plus) => Some(lex.pop()?),
-      (Tok::Minus, Tok::Minus) => Some(lex.pop()?),
-      (Tok::Star, Tok::Star) => Some(lex.pop()?),
-      (Tok::Slash, Tok::Slash) => Some(lex.pop()?),
-      (Tok::Rem, Tok::Rem) => Some(lex.pop()?),
-      (Tok::Bang, Tok::Bang) => Some(lex.pop()?),
-      (Tok::Amp, Tok::Amp) => Some(lex.pop()?),
-      (Tok::Tilde, Tok::Tilde) => Some(lex.pop()?),
-      (Tok::Equals, Tok::Equals) => Some(lex.pop()?),
-      (Tok::Semicolon, Tok::Semicolon) => Some(lex.pop()?),
-      (Tok::EqEq, Tok::EqEq) => Some(lex.pop()?),
-      (Tok::Less, Tok::Less) => Some(lex.pop()?),
-      (Tok::LessUnsigned, Tok::LessUnsigned) => Some(lex.pop()?),
-      (Tok::Greater, Tok::Greater) => Some(lex.pop()?),
-      (Tok::Or, Tok::Or) => Some(lex.pop()?),
-      (Tok::Xor, Tok::Xor) => Some(lex.pop()?),
-      (Tok::RShift, Tok::RShift) => Some(lex.pop()?),
-      (Tok::ARShift, Tok::ARShift) => Some(lex.pop()?),
-      (Tok::LShift, Tok::LShift) => Some(lex.pop()?),
-      (Tok::Let, Tok::Let) => Some(lex.pop()?),
-      (Tok::LParen, Tok::LParen) => Some(lex.pop()?),
-      (Tok::RParen, Tok::RParen) => Some(lex.pop()?),
-      (Tok::LBracket, Tok::LBracket) => Some(lex.pop()?),
-      (Tok::RBracket, Tok::RBracket) => Some(lex.pop()?),
-      (Tok::If, Tok::If) => Some(lex.pop()?),
-      (Tok::Else, Tok::Else) => Some(lex.pop()?),
-      (Tok::While, Tok::While) => Some(lex.pop()?),
-      (Tok::Comma, Tok::Comma) => Some(lex.pop()?),
-      (Tok::String(_), Tok::String(_)) => Some(lex.pop()?),
-      (Tok::Set, Tok::Set) => Some(lex.pop()?),
-      (_, _) => {
-        // println!("expected {:?} got {:?}", e, t);
-        None
-      },
-    }
+  // only which kind of token it is matters, not what's in it
+  if std::mem::discriminant(lex.peek()?) == std::mem::discriminant(&expected) {
+    lex.pop()
   } else {
     None
   }
 }
 
 fn expect<'a>(lex: &mut Lex<'a>, expected: Tok, message: &'static str) -> Option<Tok<'a>> {
-  match match_tok(lex, expected) {
-    Some(t) => Some(t),
-    None => {
-      eprintln!("{}", message);
-      None
-    }
+  let t = match_tok(lex, expected);
+  if t.is_none() {
+    lex.missing(format!("{}, found {}", message, lex.next_text()));
   }
+  t
 }
 
-fn synchronize<'a>(lex: &mut Lex) {
-  while let Some(t) = lex.pop() {
-    if t == Tok::Semicolon {
+/**
+ * Skips ahead to where the next statement probably starts after a syntax
+ * error: past a `;` or a whole `{ }`, or up to a keyword that starts a
+ * statement, or a `}` that closes the block the error was in. `depth` is how
+ * many `{` the statement with the error had opened, which get closed first.
+ */
+fn synchronize(lex: &mut Lex, mut depth: usize) {
+  loop {
+    // not peek, which would drop doc comments
+    let statement = matches!(lex.ahead.front().map(|(t, _)| t),
+      Some(Tok::Let | Tok::Set | Tok::If | Tok::While | Tok::For | Tok::Loop | Tok::LoopLabel(_)
+        | Tok::Match | Tok::Break | Tok::Continue | Tok::Fn | Tok::Attr(_) | Tok::Struct
+        | Tok::Const | Tok::Enum | Tok::Return | Tok::DocComment(_)));
+    if depth == 0 && (statement || matches!(lex.peek(), Some(Tok::RBracket))) {
       return;
     }
+    match lex.pop() {
+      None => return,
+      Some(Tok::Semicolon) if depth == 0 => return,
+      Some(Tok::LBracket) => depth += 1,
+      Some(Tok::RBracket) => {
+        depth -= 1;
+        if depth == 0 {
+          return;
+        }
+      },
+      _ => (),
+    }
   }
 }
 
-fn parse_assign<'a>(lex: &mut Lex) -> Option<Stmt> {
+fn parse_assign(lex: &mut Lex) -> Option<Stmt> {
   match_tok(lex, Tok::Set)?;
+  lex.diag.warn(lex.last, "'set' is deprecated, write 'x = value;' instead");
 
-  let name = if let Some(Tok::Ident(name)) = match_tok(lex, Tok::Ident("" as _)) {
-    name.into()
-  } else {
-    eprintln!("ERR: expected identifier after 'set'.");
-    synchronize(lex);
-    return None;
-  };
+  let target = parse_expr(lex)?;
 
-  if let None = match_tok(lex, Tok::Equals) {
-    eprintln!("ERR: expected '=' after set identifier.");
-    synchronize(lex);
+  if !matches!(lex.peek(), Some(Tok::Equals) | Some(Tok::OpAssign(_))) {
+    lex.error("expected '=' after set identifier.");
     return None;
   }
-
-  let val = if let Some(e) = parse_expr(lex) {
-    e
+  let s = parse_assign_rest(lex, target)?;
+  if match_tok(lex, Tok::Semicolon).is_some() {
+    Some(s)
   } else {
-    eprintln!("ERR: expression expected in assignment");
-    synchronize(lex);
-    return None;
+    lex.missing(format!("semicolon expected after assignment, found {}", lex.next_text()));
+    None
+  }
+}
+
+fn parse_expr_stmt(lex: &mut Lex) -> Option<Stmt> {
+  let s = parse_simple_stmt(lex)?;
+  if match_tok(lex, Tok::Semicolon).is_some() {
+    return Some(s);
+  }
+  match s {
+    Stmt::ExprStmt(_) => lex.missing(format!("semicolon expected after expression, found {}", lex.next_text())),
+    _ => lex.missing(format!("semicolon expected after assignment, found {}", lex.next_text())),
+  }
+  None
+}
+
+/**
+ * An expression or an assignment, without the `;` (the step of a `for` loop
+ * doesn't have one). Both start with an expression, so whichever it is only
+ * becomes clear at the token after it: `=` or something like `+=` makes it
+ * an assignment.
+ */
+fn parse_simple_stmt(lex: &mut Lex) -> Option<Stmt> {
+  let e = parse_expr(lex)?;
+  if matches!(lex.peek(), Some(Tok::Equals) | Some(Tok::OpAssign(_))) {
+    return parse_assign_rest(lex, e);
+  }
+  Some(Stmt::ExprStmt(e))
+}
+
+/**
+ * The rest of an assignment to `target`, starting at the `=`. Compound
+ * assignments turn into plain ones, so `x += 1;` is `x = x + 1;`. For
+ * `*p += 1;` that means `p` is evaluated twice.
+ */
+fn parse_assign_rest(lex: &mut Lex, target: Expr) -> Option<Stmt> {
+  let op = match lex.pop() {
+    Some(Tok::OpAssign(op)) => Some(op),
+    _ => None, // plain =
   };
 
-  if let Some(_) = match_tok(lex, Tok::Semicolon) {
-    Some(Stmt::Assignment(name, val))
-  } else {
-    eprintln!("ERR: semicolon expected after assignment, got {:?}", lex.peek());
-    synchronize(lex);
-    None
+  let val = parse_expr(lex)?;
+  let val = match op {
+    Some(op) => Expr::Bin(Box::new(target.clone()), op, Box::new(val)),
+    None => val,
+  };
+
+  match target {
+    Expr::Ident(name) => Some(Stmt::Assignment(name, val)),
+    Expr::Unary(UnaryOp::Deref(width), addr) => Some(Stmt::Store(*addr, val, width)),
+    Expr::Field(..) => Some(Stmt::SetField(target, val)),
+    _ => {
+      lex.error("can only assign to a variable, a field or through a pointer (*p = ...)");
+      None
+    },
   }
 }
 
-fn parse_expr_stmt<'a>(lex: &mut Lex) -> Option<Stmt> {
-  let e = Stmt::ExprStmt(parse_expr(lex)?);
-  if let Some(_) = match_tok(lex, Tok::Semicolon) {
-    Some(e)
-  } else {
-    eprintln!("ERR: syntax error - semicolon expected in expression statement.");
-    synchronize(lex);
-    None
+/// errors if `v` doesn't fit in 32 bits, signed or unsigned
+fn check_lit(v: i64, lex: &mut Lex) -> i64 {
+  if !(i32::MIN as i64..=u32::MAX as i64).contains(&v) {
+    if v < 0 {
+      lex.error_last(format!("{} doesn't fit in 32 bits", v));
+    } else {
+      lex.error_last(format!("{} doesn't fit in 32 bits", lex.text()));
+    }
   }
+  v
 }
 
-fn parse_atom<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_atom(lex: &mut Lex) -> Option<Expr> {
   match lex.pop() {
     Some(Tok::Ident(i)) => {
       Some(Expr::Ident(i.into()))
     },
     Some(Tok::Lit(l)) => {
-      Some(Expr::Lit(l))
+      Some(Expr::Lit(check_lit(l, lex)))
     },
     Some(Tok::String(s)) => {
-      Some(Expr::String(s.into()))
+      Some(Expr::String(s))
     },
+    Some(Tok::If) => parse_if_expr(lex),
     Some(Tok::LParen) => {
       let ret = parse_expr(lex);
       match lex.peek() {
@@ -389,28 +1201,48 @@ fn parse_atom<'a>(lex: &mut Lex) -> Option<Expr> {
           ret
         },
         _ => {
-          eprintln!("ERR: expected )");
+          lex.error(format!("expected ), found {}", lex.next_text()));
           None
         }
       }
     }
     other => {
-      other.map(|tok| lex.push(tok)); // un-eat token if it isnt valid
+      if let Some(tok) = other { lex.push(tok) } // un-eat token if it isnt valid
+      lex.error(format!("expected an expression, found {}", lex.next_text()));
       None
     },
   }
 }
 
-fn parse_call<'a>(lex: &mut Lex) -> Option<Expr> {
+/// `if c { a } else { b }` as a value, after the `if`. Each side is a single
+/// expression, and the else is required.
+fn parse_if_expr(lex: &mut Lex) -> Option<Expr> {
+  let cond = parse_expr(lex)?;
+  expect(lex, Tok::LBracket, "expected { after if")?;
+  let then = parse_expr(lex)?;
+  expect(lex, Tok::RBracket, "expected } after the value of an if")?;
+  expect(lex, Tok::Else, "an if used as a value needs an else")?;
+  let otherwise = if match_tok(lex, Tok::If).is_some() {
+    parse_if_expr(lex)?
+  } else {
+    expect(lex, Tok::LBracket, "expected { after else")?;
+    let e = parse_expr(lex)?;
+    expect(lex, Tok::RBracket, "expected } after the value of an else")?;
+    e
+  };
+  Some(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)))
+}
+
+fn parse_call(lex: &mut Lex) -> Option<Expr> {
   let first = parse_atom(lex)?;
   match (first, lex.peek()) {
     (Expr::Ident(s), Some(Tok::LParen)) => { // valid call
       lex.pop(); // eat (
       if let Some(Tok::RParen) = lex.peek() { // no params
         lex.pop(); // eat )
-        return Some(Expr::Call(s, vec![]));
-      } else if let Some(e) = parse_expr(lex) { // one or more params
-        let mut params = vec![e];
+        Some(Expr::Call(s, vec![]))
+      } else { // one or more params
+        let mut params = vec![parse_expr(lex)?];
         loop {
           // if matches!(lex.peek(), Some(Tok::RParen)) {
           //   break;
@@ -419,46 +1251,75 @@ fn parse_call<'a>(lex: &mut Lex) -> Option<Expr> {
             Some(Tok::RParen) => break,
             Some(Tok::Comma) => (),
             _ => {
-              eprintln!("ERR: expected comma between call parameters");
+              lex.error(format!("expected comma between call parameters, found {}", lex.next_text()));
               return None;
             }
           }
           lex.pop(); // eat comma
-          match parse_expr(lex) {
-            Some(e) => params.push(e),
-            None => return None, // should this report an error?
-          }
+          params.push(parse_expr(lex)?);
         }
         lex.pop(); // eat )
-        return Some(Expr::Call(s, params));
-      } else { // invalid
-        eprintln!("ERR: expected ')' or expression in function call");
-        return None;
+        Some(Expr::Call(s, params))
       }
     },
     (atom, _) => Some(atom) // something else
   }
 }
 
-fn parse_unary<'a>(lex: &mut Lex) -> Option<Expr> {
+/// any number of `.field` and `->field` after a call or atom
+fn parse_field(lex: &mut Lex) -> Option<Expr> {
+  let mut e = parse_call(lex)?;
+  loop {
+    if match_tok(lex, Tok::Arrow).is_some() {
+      e = Expr::Unary(UnaryOp::Deref(Width::Word), Box::new(e));
+    } else if match_tok(lex, Tok::Dot).is_none() {
+      return Some(e);
+    }
+    match match_tok(lex, Tok::Ident("" as _)) {
+      Some(Tok::Ident(name)) => e = Expr::Field(Box::new(e), Member::new(name.into())),
+      _ => {
+        lex.error("expected field name after . or ->");
+        return None;
+      },
+    }
+  }
+}
+
+fn parse_unary(lex: &mut Lex) -> Option<Expr> {
   let operator = match lex.peek() {
     Some(Tok::Amp) => UnaryOp::Addr,
-    Some(Tok::Star) => UnaryOp::Deref,
+    // the type checker works out how wide it really is
+    Some(Tok::Star) => UnaryOp::Deref(Width::Word),
     Some(Tok::Minus) => UnaryOp::Neg,
     Some(Tok::Tilde) => UnaryOp::Not,
     Some(Tok::Bang) => UnaryOp::BoolNot,
     _ => {
-      return parse_call(lex)
+      return parse_field(lex)
     }
   };
   lex.pop(); // eat operator
 
   let operand = parse_unary(lex)?;
+  // -5 is folded into a literal here, so it's still a constant everywhere
+  // one is wanted. It's checked for being too big again once it's negative,
+  // one, unless it was already too big without the -
+  if let (UnaryOp::Neg, Expr::Lit(v)) = (operator, &operand) {
+    return Some(Expr::Lit(if *v > u32::MAX as i64 { -v } else { check_lit(-v, lex) }));
+  }
   Some(Expr::Unary(operator, Box::new(operand)))
 }
 
-fn parse_term<'a>(lex: &mut Lex) -> Option<Expr>  {
-  let first = parse_unary(lex)?;
+/// `value as type`, which binds tighter than any binary operator
+fn parse_cast(lex: &mut Lex) -> Option<Expr> {
+  let mut e = parse_unary(lex)?;
+  while match_tok(lex, Tok::As).is_some() {
+    e = Expr::Cast(Box::new(e), parse_type(lex)?);
+  }
+  Some(e)
+}
+
+fn parse_term(lex: &mut Lex) -> Option<Expr>  {
+  let first = parse_cast(lex)?;
   let op = match lex.peek() {
     Some(Tok::Star) => {
       lex.pop()?; // eat op
@@ -478,7 +1339,7 @@ fn parse_term<'a>(lex: &mut Lex) -> Option<Expr>  {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_sum<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_sum(lex: &mut Lex) -> Option<Expr> {
   let first = parse_term(lex)?;
   let op = match lex.peek() {
     Some(Tok::Plus) => {
@@ -495,7 +1356,7 @@ fn parse_sum<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_shift<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_shift(lex: &mut Lex) -> Option<Expr> {
   let first = parse_sum(lex)?;
   let op = match lex.peek() {
     Some(Tok::RShift) => {
@@ -516,7 +1377,7 @@ fn parse_shift<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_less<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_less(lex: &mut Lex) -> Option<Expr> {
   let first = parse_shift(lex)?;
   let op = match lex.peek() {
     Some(Tok::Less) => {
@@ -537,7 +1398,7 @@ fn parse_less<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_equality<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_equality(lex: &mut Lex) -> Option<Expr> {
   let first = parse_less(lex)?;
   let op = match lex.peek() {
     Some(Tok::EqEq) => {
@@ -550,7 +1411,7 @@ fn parse_equality<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_and<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_and(lex: &mut Lex) -> Option<Expr> {
   let first = parse_equality(lex)?;
   let op = match lex.peek() {
     Some(Tok::Amp) => {
@@ -563,7 +1424,7 @@ fn parse_and<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_xor<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_xor(lex: &mut Lex) -> Option<Expr> {
   let first = parse_and(lex)?;
   let op = match lex.peek() {
     Some(Tok::Xor) => {
@@ -576,7 +1437,7 @@ fn parse_xor<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_or<'a>(lex: &mut Lex) -> Option<Expr> {
+fn parse_or(lex: &mut Lex) -> Option<Expr> {
   let first = parse_xor(lex)?;
   let op = match lex.peek() {
     Some(Tok::Or) => {
@@ -589,6 +1450,18 @@ fn parse_or<'a>(lex: &mut Lex) -> Option<Expr> {
   Some(Expr::Bin(Box::new(first), op, Box::new(second)))
 }
 
-fn parse_expr<'a>(lex: &mut Lex) -> Option<Expr> {
-  parse_or(lex)
+/// `c ? a : b`, which has the lowest precedence and groups to the right
+fn parse_ternary(lex: &mut Lex) -> Option<Expr> {
+  let cond = parse_or(lex)?;
+  if match_tok(lex, Tok::Question).is_none() {
+    return Some(cond);
+  }
+  let then = parse_expr(lex)?;
+  expect(lex, Tok::Colon, "expected : in ?: expression")?;
+  let otherwise = parse_ternary(lex)?;
+  Some(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)))
+}
+
+fn parse_expr(lex: &mut Lex) -> Option<Expr> {
+  parse_ternary(lex)
 }
@@ -0,0 +1,224 @@
+use std::time::{Duration, Instant};
+
+use crate::asm::Instr;
+use crate::expr::Block;
+
+/// Every optimization the compiler knows how to run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pass {
+  Inline,
+  Lvn,
+  Dce,
+  Licm,
+  Peephole,
+  Ecalls,
+}
+
+/// what a pass works on, which decides when it gets to run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+  /// the syntax tree, before any code is generated
+  Ast,
+  /// each function's instructions, before the stack frame is set up
+  Ir,
+  /// the finished instructions of each function
+  Machine,
+}
+
+impl Pass {
+  pub const ALL: [Pass; 6] = [Pass::Inline, Pass::Ecalls, Pass::Lvn, Pass::Dce, Pass::Licm, Pass::Peephole];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Pass::Inline => "inline",
+      Pass::Lvn => "lvn",
+      Pass::Dce => "dce",
+      Pass::Licm => "licm",
+      Pass::Peephole => "peephole",
+      Pass::Ecalls => "ecalls",
+    }
+  }
+
+  pub fn description(self) -> &'static str {
+    match self {
+      Pass::Inline => "replace calls to small functions with their body",
+      Pass::Lvn => "reuse values already in a register (local value numbering)",
+      Pass::Dce => "remove instructions whose result is never used",
+      Pass::Licm => "move loop-invariant computations out of loops",
+      Pass::Peephole => "remove jumps to the next instruction and unreachable code",
+      Pass::Ecalls => "make system calls directly instead of through the standard library",
+    }
+  }
+
+  fn stage(self) -> Stage {
+    match self {
+      Pass::Inline => Stage::Ast,
+      Pass::Ecalls | Pass::Lvn | Pass::Dce | Pass::Licm => Stage::Ir,
+      Pass::Peephole => Stage::Machine,
+    }
+  }
+}
+
+impl std::str::FromStr for Pass {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Pass::ALL.into_iter().find(|p| p.name() == s).ok_or_else(|| {
+      let names: Vec<&str> = Pass::ALL.iter().map(|p| p.name()).collect();
+      format!("unknown pass {} (expected one of {})", s, names.join(", "))
+    })
+  }
+}
+
+/// The `-O` presets.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OptLevel {
+  O0,
+  O1,
+  O2,
+  /// optimize for size
+  Os,
+}
+
+impl std::str::FromStr for OptLevel {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "0" => Ok(OptLevel::O0),
+      "1" => Ok(OptLevel::O1),
+      "2" => Ok(OptLevel::O2),
+      "s" => Ok(OptLevel::Os),
+      other => Err(format!("unknown optimization level -O{} (expected 0, 1, 2 or s)", other)),
+    }
+  }
+}
+
+/// time spent in a pass and how much it changed the code, added up over every
+/// time it ran.
+struct Timing {
+  pass: Pass,
+  time: Duration,
+  before: usize,
+  after: usize,
+}
+
+/**
+ * Decides which passes run and in what order, and keeps track of how long
+ * they take. Passes on the syntax tree run first, then the passes on
+ * instructions (in the order they were given), then the machine code ones.
+ */
+pub struct PassManager {
+  passes: Vec<Pass>,
+  inline_threshold: usize,
+  time_passes: bool,
+  timings: Vec<Timing>,
+}
+
+impl PassManager {
+  pub fn new(level: OptLevel) -> Self {
+    use Pass::*;
+    let (passes, inline_threshold) = match level {
+      OptLevel::O0 => (vec![], 0),
+      OptLevel::O1 => (vec![Inline, Lvn, Dce, Licm, Peephole], 12),
+      // another round of lvn and dce cleans up after licm.
+      OptLevel::O2 => (vec![Inline, Ecalls, Lvn, Dce, Licm, Lvn, Dce, Peephole], 40),
+      // licm makes loops faster but can leave copies behind, so it's left
+      // out. Only functions about as small as a call are inlined.
+      OptLevel::Os => (vec![Inline, Ecalls, Lvn, Dce, Peephole], 4),
+    };
+    Self { passes, inline_threshold, time_passes: false, timings: vec![] }
+  }
+
+  /// run exactly these passes instead of the preset ones. They still run a
+  /// stage at a time, so one that can't run where it was given gets a
+  /// warning.
+  pub fn set_passes(&mut self, passes: Vec<Pass>) {
+    for (i, p) in passes.iter().enumerate() {
+      if let Some(later) = passes[..i].iter().find(|q| q.stage() > p.stage()) {
+        eprintln!("WARN: {} runs before {} anyway, since passes on the syntax tree run first, then the ones on instructions, then the ones on machine code",
+          p.name(), later.name());
+      }
+    }
+    self.passes = passes;
+  }
+
+  pub fn set_time_passes(&mut self, time_passes: bool) {
+    self.time_passes = time_passes;
+  }
+
+  pub fn run_ast(&mut self, ast: &mut Block) {
+    for pass in self.stage(Stage::Ast) {
+      let threshold = self.inline_threshold;
+      self.timed(pass, ast, crate::inline::size, |ast| match pass {
+        Pass::Inline => crate::inline::inline(ast, threshold),
+        _ => unreachable!(),
+      });
+    }
+  }
+
+  pub fn run_ir(&mut self, instrs: &mut Vec<Instr>) {
+    for pass in self.stage(Stage::Ir) {
+      self.timed(pass, instrs, |i| count_instrs(i), |instrs| match pass {
+        Pass::Lvn => crate::opt::lvn(instrs),
+        Pass::Dce => crate::opt::dce(instrs),
+        Pass::Licm => crate::opt::licm(instrs),
+        Pass::Ecalls => crate::opt::inline_ecalls(instrs),
+        _ => unreachable!(),
+      });
+    }
+  }
+
+  pub fn run_machine(&mut self, instrs: &mut Vec<Instr>) {
+    for pass in self.stage(Stage::Machine) {
+      self.timed(pass, instrs, |i| count_instrs(i), |instrs| match pass {
+        Pass::Peephole => crate::opt::peephole(instrs),
+        _ => unreachable!(),
+      });
+    }
+  }
+
+  fn stage(&self, stage: Stage) -> Vec<Pass> {
+    self.passes.iter().copied().filter(|p| p.stage() == stage).collect()
+  }
+
+  fn timed<T>(&mut self, pass: Pass, code: &mut T, size: fn(&T) -> usize, run: impl FnOnce(&mut T)) {
+    if !self.time_passes {
+      run(code);
+      return;
+    }
+    let before = size(code);
+    let start = Instant::now();
+    run(code);
+    let time = start.elapsed();
+    let after = size(code);
+
+    match self.timings.iter_mut().find(|t| t.pass == pass) {
+      Some(t) => {
+        t.time += time;
+        t.before += before;
+        t.after += after;
+      },
+      None => self.timings.push(Timing { pass, time, before, after }),
+    }
+  }
+
+  /// prints how long each pass took to stderr, if `--time-passes` was given.
+  pub fn report(&self) {
+    if !self.time_passes {
+      return;
+    }
+    eprintln!("{:<10} {:>12} {:>8} {:>8} {:>8}", "pass", "time (ms)", "before", "after", "change");
+    for t in &self.timings {
+      // the inliner counts syntax tree nodes instead of instructions
+      eprintln!("{:<10} {:>12.3} {:>8} {:>8} {:>+8}{}",
+        t.pass.name(), t.time.as_secs_f64() * 1000.0, t.before, t.after,
+        t.after as i64 - t.before as i64,
+        if t.pass.stage() == Stage::Ast { " (nodes)" } else { "" });
+    }
+  }
+}
+
+fn count_instrs(instrs: &[Instr]) -> usize {
+  instrs.iter().filter(|i| !matches!(i, Instr::Label(_))).count()
+}
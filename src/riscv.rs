@@ -1,11 +1,18 @@
-use std::{collections::HashMap};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
 
-use crate::expr::{Expr, Stmt, UnaryOp, Block};
+use crate::expr::{Expr, Stmt, UnaryOp, BinOp, Block, FnDecl, Inlined, Loop, Arm, Pattern, Type, Width, Member};
+use crate::diag::{Span, Spanned};
+use crate::intern::Symbol;
+use crate::passes::{OptLevel, PassManager};
+use crate::asm::{Instr, RegSet, arg_reg, ARG_REGS, CALLEE_SAVED, CALLER_SAVED};
 
+/// the label execution starts at
+const ENTRY: &str = "__start";
 
 
-#[derive(Copy, Clone, Debug)]
-pub struct Reg (u8);
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Reg (pub(crate) u8);
 
 // is this really necessary? idk, but i wrote it before anything else and it
 // might pay off eventually.
@@ -76,7 +83,6 @@ pub const T4: Reg = Reg(29);
 pub const T5: Reg = Reg(30);
 pub const T6: Reg = Reg(31);
 
-const STDLIB: &'static str = include_str!("../resources/stdlib.s");
 
 impl std::fmt::Display for Reg {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -116,29 +122,121 @@ impl RegMap {
   fn free_reg(&mut self, r: Reg) {
     self.map[r.0 as usize] = RState::Free;
   }
+
+  /// temporaries currently holding something
+  fn in_use(&self) -> Vec<Reg> {
+    [T0, T1, T2, T3, T4, T5, T6].into_iter()
+      .filter(|r| self.map[r.0 as usize] == RState::Used)
+      .collect()
+  }
+}
+
+/// where a variable lives, and how it's loaded and stored
+#[derive(Clone, Debug)]
+enum Var {
+  /// a label in .data
+  Global(String, Width),
+  /// an offset from the frame pointer
+  Local(i32, Width),
+}
+
+/**
+ * The stack frame of the function being compiled. Going down from fp, it
+ * holds the return address, the caller's fp, then locals (parameters
+ * included), then whichever s registers the function ends up using:
+ *
+ * ```text
+ * -4(fp)  ra
+ * -8(fp)  old fp
+ * -12(fp) first local
+ * ...
+ * ```
+ */
+struct Frame {
+  /// offset, width and size in bytes of each local
+  scopes: Vec<HashMap<Symbol, (i32, Width, i32)>>,
+  /// bytes of locals in scope right now
+  size: i32,
+  /// the most bytes of locals ever in scope at once
+  max_size: i32,
+}
+
+impl Frame {
+  fn new() -> Self {
+    Self { scopes: vec![], size: 0, max_size: 0 }
+  }
+
+  /// every local gets a whole number of words, however narrow it is
+  fn alloc(&mut self, name: &Symbol, size: i32, width: Width) -> Option<i32> {
+    let scope = self.scopes.last_mut().unwrap();
+    if scope.contains_key(name) {
+      eprintln!("ERR: Redeclaration of variable {}", name);
+      return None;
+    }
+    let size = (size + 3) / 4 * 4;
+    self.size += size;
+    self.max_size = self.max_size.max(self.size);
+    let offset = -8 - self.size;
+    scope.insert(*name, (offset, width, size));
+    Some(offset)
+  }
+
+  fn get_var(&self, name: &Symbol) -> Option<(i32, Width)> {
+    self.scopes.iter().rev().find_map(|s| s.get(name).map(|&(offset, width, _)| (offset, width)))
+  }
 }
 
 struct SymTab {
-  pub data: HashMap<String, (String, String)>, // name -> (label, initial value)
-  pub strings: HashMap<String, String>, // label -> contents
+  /// label -> what goes after it (like `.word 5`), and what it's aligned to
+  pub data: HashMap<String, (String, i64)>,
+  /// variable name -> label, for each block we're in (innermost last)
+  scopes: Vec<HashMap<Symbol, (String, Width)>>,
+  pub strings: HashMap<Vec<u8>, String>, // contents -> label
+  /// jump tables for `match`: label -> the code labels in it
+  tables: Vec<(String, Vec<String>)>,
+  /// names of `const`s and `enum` variants. The type checker already put
+  /// their values in wherever they're used, so they don't take up any space.
+  consts: HashSet<Symbol>,
 }
 
 impl SymTab {
-  fn decl(&mut self, name: String, initial: String) -> Option<()> {
-    if self.data.contains_key(&name) {
+  /// defining one twice was reported by the type checker
+  fn constant(&mut self, name: &Symbol) {
+    self.consts.insert(*name);
+  }
+
+  /// None if `name` is a constant, which can't be declared as a variable.
+  /// The type checker reported it.
+  fn not_const(&self, name: &Symbol) -> Option<()> {
+    if self.consts.contains(name) {
+      return None;
+    }
+    Some(())
+  }
+
+  fn decl(&mut self, name: Symbol, data: String, align: i64, width: Width) -> Option<()> {
+    self.not_const(&name)?;
+    if self.scopes.last().unwrap().contains_key(&name) {
       eprintln!("ERR: Redeclaration of variable {}", name);
       return None;
     }
-    let lbl = format!("__var_{}", &name);
-    self.data.insert(name, (lbl, initial));
+    // the same name declared in another block needs a label of its own
+    let mut lbl = mangle("__var", name.as_str());
+    let mut n = 1;
+    while self.data.contains_key(&lbl) {
+      n += 1;
+      lbl = mangle("__var", &format!("{}#{}", name, n));
+    }
+    self.data.insert(lbl.clone(), (data, align));
+    self.scopes.last_mut().unwrap().insert(name, (lbl, width));
     Some(())
   }
 
-  fn get_var(&self, name: &str) -> Option<&str> {
-    self.data.get(name).map(|tup| &tup.0 as _)
+  fn get_var(&self, name: &Symbol) -> Option<&(String, Width)> {
+    self.scopes.iter().rev().find_map(|s| s.get(name))
   }
 
-  fn add_string(&mut self, s: String) -> String {
+  fn add_string(&mut self, s: Vec<u8>) -> String {
     /* PERF: avoid cloning!! slow!! */
     if !self.strings.contains_key(&s) {
       self.strings.insert(s.clone(), format!("__str_{}", self.strings.len()));
@@ -148,21 +246,250 @@ impl SymTab {
 
   fn dump_data_asm(&self) {
     println!(".data");
-    for (contents, label) in &self.strings {
-      println!(r#"{}: .asciz "{}" "#, label, contents);
+    // widest first, so everything ends up aligned without any padding
+    for (label, targets) in &self.tables {
+      println!("{}:", label);
+      println!("    .word {}", targets.join(", "));
     }
-    for (label, initial) in self.data.values() {
+    let mut data: Vec<_> = self.data.iter().collect();
+    data.sort_by_key(|(label, (_, align))| (-align, label.as_str()));
+    for (label, (data, _)) in data {
       println!("{}:", label);
-      println!("    .word {}", initial);
+      println!("    {}", data);
+    }
+    for (contents, label) in &self.strings {
+      println!("{}: {}", label, string_directive(contents));
     }
-    println!("");
+    println!();
   }
 
   fn new() -> Self {
-    Self {data: HashMap::new(), strings: HashMap::new()}
+    Self {data: HashMap::new(), scopes: vec![HashMap::new()], strings: HashMap::new(), tables: vec![], consts: HashSet::new()}
+  }
+}
+
+
+/**
+ * How a string goes in .data. Only plain text goes in quotes, since
+ * assemblers don't agree on escapes, so quotes, backslashes and bytes that
+ * aren't printable ASCII are written out as numbers in between:
+ *
+ * ```text
+ * .ascii "two"
+ * .byte 10
+ * .asciz "lines"
+ * ```
+ */
+fn string_directive(contents: &[u8]) -> String {
+  let plain = |c: &u8| (b' '..=b'~').contains(c) && *c != b'"' && *c != b'\\';
+  let mut lines = vec![];
+  let mut rest = contents;
+  while !rest.is_empty() {
+    let mut n = rest.iter().take_while(|c| plain(c)).count();
+    if n > 0 {
+      // all ASCII, so this can't fail
+      let text = std::str::from_utf8(&rest[..n]).unwrap();
+      let directive = if n == rest.len() { ".asciz" } else { ".ascii" };
+      lines.push(format!(r#"{} "{}""#, directive, text));
+    } else {
+      n = rest.iter().take_while(|c| !plain(c)).count();
+      let mut bytes: Vec<String> = rest[..n].iter().map(|c| c.to_string()).collect();
+      if n == rest.len() {
+        bytes.push("0".to_string());
+      }
+      lines.push(format!(".byte {}", bytes.join(", ")));
+    }
+    rest = &rest[n..];
+  }
+  if lines.is_empty() {
+    lines.push(".byte 0".to_string());
+  }
+  lines.join("\n    ")
+}
+
+/**
+ * The assembler label for something the user named. Each kind of symbol gets
+ * its own prefix (`__var`, `__fn`), so user names can't clash with the
+ * standard library, strings, or the compiler's own labels.
+ *
+ * The names the compiler makes up, for inlined locals and globals declared
+ * again, have a `#` in them, which isn't allowed in a label. Those get an `x`
+ * on the prefix and are spelled out, with `_` written as `__` and anything
+ * else as `_` and its hex code, so `g#2` becomes `__varx_g_232`.
+ */
+fn mangle(kind: &str, name: &str) -> String {
+  if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+    return format!("{}_{}", kind, name);
+  }
+  let mut out = format!("{}x_", kind);
+  for c in name.chars() {
+    match c {
+      '_' => out.push_str("__"),
+      c if c.is_ascii_alphanumeric() => out.push(c),
+      c => out.push_str(&format!("_{:02x}", c as u32)),
+    }
+  }
+  out
+}
+
+/**
+ * The value of `e`, if it can be worked out without running anything. This
+ * follows what the instructions would do, so everything wraps around at 32
+ * bits and dividing by zero gives -1 (and the remainder is the dividend).
+ */
+pub fn const_eval(e: &Expr) -> Option<i64> {
+  use crate::expr::BinOp::*;
+  let v = match e {
+    Expr::Lit(v) => *v as i32,
+    Expr::Unary(op, e) => {
+      let v = const_eval(e)? as i32;
+      match op {
+        UnaryOp::Neg => v.wrapping_neg(),
+        UnaryOp::Not => !v,
+        UnaryOp::BoolNot => (v == 0) as i32,
+        UnaryOp::Deref(_) | UnaryOp::Addr => return None,
+      }
+    },
+    Expr::Bin(l, op, r) => {
+      let (l, r) = (const_eval(l)? as i32, const_eval(r)? as i32);
+      let shift = (r & 31) as u32;
+      match op {
+        Add => l.wrapping_add(r),
+        Sub => l.wrapping_sub(r),
+        Mul => l.wrapping_mul(r),
+        Div if r == 0 => -1,
+        Div => l.wrapping_div(r),
+        Rem if r == 0 => l,
+        Rem => l.wrapping_rem(r),
+        Srl => ((l as u32) >> shift) as i32,
+        Sra => l >> shift,
+        Sll => l << shift,
+        And => l & r,
+        Or => l | r,
+        Xor => l ^ r,
+        Less => (l < r) as i32,
+        LessUnsigned => ((l as u32) < (r as u32)) as i32,
+        Greater => (l > r) as i32,
+        TestEq => (l == r) as i32,
+        DivUnsigned if r == 0 => -1,
+        DivUnsigned => ((l as u32) / (r as u32)) as i32,
+        RemUnsigned if r == 0 => l,
+        RemUnsigned => ((l as u32) % (r as u32)) as i32,
+      }
+    },
+    Expr::Cond(c, t, f) => return const_eval(if const_eval(c)? != 0 { t } else { f }),
+    Expr::Cast(e, to) => {
+      let v = const_eval(e)? as i32;
+      match to {
+        Type::I8 => v as i8 as i32,
+        Type::U8 => v as u8 as i32,
+        Type::I16 => v as i16 as i32,
+        Type::U16 => v as u16 as i32,
+        _ => v,
+      }
+    },
+    _ => return None,
+  };
+  Some(v as i64)
+}
+
+/// both sides of a conditional together can cost at most this much for it to
+/// be compiled without branches
+const BRANCHLESS_MAX_COST: usize = 6;
+
+/**
+ * Roughly how many instructions it takes to work out `e`, or None if it
+ * can't be evaluated when its value isn't wanted: anything that calls,
+ * loads through a pointer or divides (which is slow, or a call on rv32i).
+ */
+fn branchless_cost(e: &Expr) -> Option<usize> {
+  use crate::expr::BinOp::*;
+  match e {
+    Expr::Lit(_) | Expr::Ident(_) | Expr::String(_) => Some(1),
+    Expr::Unary(UnaryOp::Neg | UnaryOp::Not | UnaryOp::BoolNot, e) => Some(1 + branchless_cost(e)?),
+    Expr::Cast(e, to) => Some(narrowing_cost(to) + branchless_cost(e)?),
+    Expr::Bin(_, Mul | Div | Rem | DivUnsigned | RemUnsigned, _) => None,
+    Expr::Bin(l, _, r) => Some(1 + branchless_cost(l)? + branchless_cost(r)?),
+    _ => None,
+  }
+}
+
+/// whether working out `e` calls anything, which overwrites the a registers.
+/// On rv32i that includes multiplying and dividing.
+fn calls(e: &Expr, target: Target) -> bool {
+  use crate::expr::BinOp::*;
+  match e {
+    Expr::Call(..) | Expr::Inline(_) => true,
+    Expr::Bin(_, Mul | Div | Rem | DivUnsigned | RemUnsigned, _) if target == Target::Rv32i => true,
+    Expr::Bin(l, _, r) => calls(l, target) || calls(r, target),
+    Expr::Unary(_, e) | Expr::Cast(e, _) | Expr::Field(e, _) => calls(e, target),
+    Expr::Cond(c, t, f) => calls(c, target) || calls(t, target) || calls(f, target),
+    Expr::Lit(_) | Expr::Ident(_) | Expr::String(_) => false,
+  }
+}
+
+/// how many instructions it takes to cut a value down to `to`
+fn narrowing_cost(to: &Type) -> usize {
+  match to {
+    Type::U8 => 1,
+    Type::I8 | Type::I16 | Type::U16 => 2,
+    _ => 0,
   }
 }
 
+/// a `match` needs at least this many cases to use a jump table
+const JUMP_TABLE_MIN_CASES: usize = 4;
+/// and its table can't have more entries than this
+const JUMP_TABLE_MAX_SIZE: i64 = 1024;
+
+/**
+ * The values each arm of a `match` handles, as sorted ranges that don't
+ * overlap: `(low, high, arm)`, including both ends. When a value matches
+ * more than one arm, the first one gets it. Stops at a `_`.
+ */
+fn match_cases(arms: &[Arm]) -> Vec<(i64, i64, usize)> {
+  let mut cases: Vec<(i64, i64, usize)> = vec![];
+  'arms: for (arm, a) in arms.iter().enumerate() {
+    for p in &a.patterns {
+      let (low, high) = match *p {
+        Pattern::Value(v) => (v, v),
+        Pattern::Range(l, h) => (l, h),
+        Pattern::Wildcard => break 'arms,
+        // not a constant, which the type checker reported
+        Pattern::Const(_) => continue,
+      };
+      // cut out whatever earlier patterns already cover
+      let mut pieces = vec![(low, high)];
+      for &(cl, ch, _) in &cases {
+        pieces = pieces.into_iter().flat_map(|(l, h)| {
+          if h < cl || l > ch {
+            return vec![(l, h)];
+          }
+          let mut rest = vec![];
+          if l < cl {
+            rest.push((l, cl - 1));
+          }
+          if h > ch {
+            rest.push((ch + 1, h));
+          }
+          rest
+        }).collect();
+      }
+      cases.extend(pieces.into_iter().map(|(l, h)| (l, h, arm)));
+    }
+  }
+  cases.sort();
+
+  // 1 | 2 | 3 is the same as 1..=3
+  let mut merged: Vec<(i64, i64, usize)> = vec![];
+  for c in cases {
+    match merged.last_mut() {
+      Some(last) if last.2 == c.2 && last.1 + 1 == c.0 => last.1 = c.1,
+      _ => merged.push(c),
+    }
+  }
+  merged
+}
 
 struct LabelCounter {
   count: u32
@@ -177,23 +504,81 @@ impl LabelCounter {
 
   fn new() -> Self { Self { count: 0 } }
 }
+/// Which flavour of RV32 to generate code for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+  /// base integer instructions only, so multiply and divide are done in software.
+  Rv32i,
+  /// with the M extension (mul, div, rem). This is what RARS implements.
+  Rv32im,
+}
+
+impl std::str::FromStr for Target {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "rv32i" => Ok(Target::Rv32i),
+      "rv32im" => Ok(Target::Rv32im),
+      other => Err(format!("unknown target {} (expected rv32i or rv32im)", other)),
+    }
+  }
+}
+
 pub struct Compiler {
   stab: SymTab,
   pub instrs: IBlock,
   regs: RegMap,
   label_counter: LabelCounter,
+  target: Target,
+  passes: PassManager,
+  /// every user function, by name
+  functions: HashMap<Symbol, FnDecl>,
+  /// size and alignment of every struct, as the type checker laid them out
+  structs: HashMap<Symbol, (i64, i64)>,
+  /// functions that have been called but not compiled yet
+  wanted: Vec<Symbol>,
+  /// compiled functions, in the order they'll be emitted
+  fn_instrs: Vec<(String, IBlock)>,
+  /// the function being compiled, if any
+  frame: Option<Frame>,
+  /// where `return` jumps to, and the register the value goes in. Inlined
+  /// bodies push their own.
+  returns: Vec<(String, Reg)>,
+  /// for each loop we're in: its name, and where `break` and `continue` jump
+  loops: Vec<(Option<Symbol>, String, String)>,
+  /// how many inlined bodies are being compiled right now. Their variables
+  /// have to be initialized every time they run, even outside of functions.
+  inline_depth: usize,
 }
 
-type IBlock = Vec<String>;
+type IBlock = Vec<Instr>;
+
+impl Default for Compiler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 
 impl Compiler {
 
   pub fn dump(&self) {
     self.stab.dump_data_asm();
     println!(".text");
-    println!("    j __start");
-    println!("{}", STDLIB);
-    println!("__start:");
+    println!("    j {}", ENTRY);
+    // only the parts of the standard library that something calls
+    let called = self.fn_instrs.iter().flat_map(|(_, instrs)| instrs).chain(&self.instrs)
+      .filter_map(|i| match i {
+        Instr::Call(name, _) => Some(name.as_str()),
+        _ => None,
+      });
+    println!("{}", crate::stdlib::link(called));
+    for (_, instrs) in &self.fn_instrs {
+      for l in instrs {
+        println!("    {}", l)
+      }
+    }
+    println!("{}:", ENTRY);
     for l in &self.instrs {
       println!("    {}", l)
     }
@@ -205,17 +590,279 @@ impl Compiler {
       instrs: vec![],
       regs: RegMap{map: [RState::Free; 32]},
       label_counter: LabelCounter::new(),
+      target: Target::Rv32im,
+      passes: PassManager::new(OptLevel::O1),
+      functions: HashMap::new(),
+      structs: HashMap::new(),
+      wanted: vec![],
+      fn_instrs: vec![],
+      frame: None,
+      returns: vec![],
+      loops: vec![],
+      inline_depth: 0,
     }
   }
 
-  pub fn compile(&mut self, stmts: Vec<Stmt>) {
+  pub fn set_target(&mut self, target: Target) {
+    self.target = target;
+  }
+
+  pub fn set_pass_manager(&mut self, passes: PassManager) {
+    self.passes = passes;
+  }
+
+  pub fn pass_manager(&self) -> &PassManager {
+    &self.passes
+  }
+
+  pub fn compile(&mut self, stmts: Block) {
+    let mut stmts = stmts;
+    // once the top level statements are done, the program exits with
+    // whatever `main` returns, or 0 if there's no `main`. This goes in before
+    // the inliner runs so a small `main` can be inlined too.
+    let main = stmts.iter().find_map(|s| match &s.node {
+      Stmt::Fn(f) if f.name == "main" => Some(f),
+      _ => None,
+    });
+    let code = match main {
+      Some(_) => Expr::Call("main".into(), vec![]),
+      None => Expr::Lit(0),
+    };
+    let exit = Stmt::ExprStmt(Expr::Call("exit2".into(), vec![code]));
+    stmts.push(Spanned::new(exit, Span::default()));
+    self.passes.run_ast(&mut stmts);
+
+    let mut main = vec![];
+    for stmt in stmts {
+      match stmt.node {
+        Stmt::Fn(f) => {
+          // one that can't be defined was reported by the type checker
+          if let Entry::Vacant(e) = self.functions.entry(f.name) {
+            e.insert(f);
+          }
+        },
+        Stmt::Struct(s) => {
+          self.structs.insert(s.name, (s.size, s.align));
+        },
+        Stmt::Const(name, _) => self.stab.constant(&name),
+        Stmt::Enum(_, variants) => variants.iter().for_each(|(name, _)| self.stab.constant(name)),
+        other => main.push(other),
+      }
+    }
+
+    // not compile_block, the top level's variables have to stay around for
+    // the functions.
     let mut b = vec![];
-    self.compile_block(&mut b, &stmts);
+    main.iter().for_each(|stmt| self.compile_stmt(&mut b, stmt));
+    self.passes.run_ir(&mut b);
+    self.passes.run_machine(&mut b);
     self.instrs = b;
+
+    // only functions that actually get called are compiled.
+    let mut done = HashSet::new();
+    while let Some(name) = self.wanted.pop() {
+      if done.insert(name) {
+        let f = self.functions[&name].clone();
+        let instrs = self.compile_function(&f);
+        self.fn_instrs.push((name.to_string(), instrs));
+      }
+    }
+  }
+
+  /**
+   * Compiles a user function, including the code to set up and tear down its
+   * stack frame. That part is added after optimizing, since it depends on
+   * which s registers loop-invariant code motion ended up using.
+   */
+  fn compile_function(&mut self, f: &FnDecl) -> IBlock {
+    let end_label = self.label_counter.next();
+    self.frame = Some(Frame::new());
+    self.returns.push((end_label.clone(), A0));
+
+    // parameters get copied into the frame like any other local, so they can
+    // be assigned to and have their address taken.
+    let mut b = vec![];
+    self.frame.as_mut().unwrap().scopes.push(HashMap::new());
+    for (i, (p, t)) in f.params.iter().zip(&f.param_types).enumerate() {
+      if let Some(var) = self.declare_var(p, String::new(), t.width()) {
+        self.store_var(&mut b, arg_reg(i), var);
+      }
+    }
+    self.compile_block(&mut b, &f.body);
+    if !matches!(f.body.last().map(|s| &s.node), Some(Stmt::Return(_))) {
+      b.push(Instr::Li(A0, 0)); // falling off the end returns 0
+    }
+    b.push(Instr::Label(end_label));
+    b.push(Instr::Ret);
+    self.passes.run_ir(&mut b);
+
+    let frame = self.frame.take().unwrap();
+    self.returns.pop();
+
+    let saved: Vec<Reg> = CALLEE_SAVED.iter()
+      .filter(|r| b.iter().any(|i| i.defs().contains(*r)))
+      .collect();
+    let locals = frame.max_size;
+    let size = (8 + locals + 4 * saved.len() as i32 + 15) / 16 * 16;
+    let save_offset = |i: usize| -12 - locals - 4 * i as i32;
+
+    let mut prologue = vec![Instr::Label(mangle("__fn", f.name.as_str()))];
+    let mut epilogue = vec![];
+    if size < 2048 {
+      prologue.extend([
+        Instr::OpImm("addi", SP, SP, -size as i64),
+        Instr::Store("sw", RA, size - 4, SP),
+        Instr::Store("sw", FP, size - 8, SP),
+        Instr::OpImm("addi", FP, SP, size as i64),
+      ]);
+      for (i, r) in saved.iter().enumerate() {
+        prologue.push(Instr::Store("sw", *r, save_offset(i), FP));
+        epilogue.push(Instr::Load("lw", *r, save_offset(i), FP));
+      }
+    } else {
+      // too far for an immediate, so the old sp goes through t0 (nothing's
+      // in it yet), and the saved registers are found from the bottom of the
+      // frame instead of fp, which is far away from them.
+      prologue.extend([
+        Instr::Li(T0, -size as i64),
+        Instr::Op("add", SP, SP, T0),
+        Instr::Op("sub", T0, SP, T0),
+        Instr::Store("sw", RA, -4, T0),
+        Instr::Store("sw", FP, -8, T0),
+        Instr::Mv(FP, T0),
+      ]);
+      if !saved.is_empty() {
+        self.add_imm(&mut epilogue, T0, FP, -size as i64, T0);
+      }
+      for (i, r) in saved.iter().enumerate() {
+        prologue.push(Instr::Store("sw", *r, size + save_offset(i), SP));
+        epilogue.push(Instr::Load("lw", *r, size + save_offset(i), T0));
+      }
+    }
+    epilogue.extend([
+      Instr::Load("lw", RA, -4, FP),
+      Instr::Mv(SP, FP),
+      Instr::Load("lw", FP, -8, SP),
+    ]);
+
+    let ret = b.pop(); // the epilogue goes right before the ret
+    prologue.append(&mut b);
+    prologue.append(&mut epilogue);
+    prologue.extend(ret);
+    self.passes.run_machine(&mut prologue);
+    prologue
   }
 
   fn compile_block(&mut self, b: &mut IBlock, stmts: &Block) {
-    stmts.iter().for_each(|stmt| self.compile_stmt(b, stmt));
+    self.push_scope();
+    stmts.iter().for_each(|stmt| self.compile_stmt(b, &stmt.node));
+    self.pop_scope();
+  }
+
+  fn push_scope(&mut self) {
+    match &mut self.frame {
+      Some(frame) => frame.scopes.push(HashMap::new()),
+      None => self.stab.scopes.push(HashMap::new()),
+    }
+  }
+
+  fn pop_scope(&mut self) {
+    match &mut self.frame {
+      Some(frame) => {
+        // the slots can be reused once their variables are out of scope
+        let scope = frame.scopes.pop().unwrap();
+        frame.size -= scope.values().map(|&(_, _, size)| size).sum::<i32>();
+      },
+      None => {
+        self.stab.scopes.pop();
+      },
+    }
+  }
+
+  /**
+   * Declares a variable in the innermost scope: a stack slot inside of a
+   * function, and a label in .data otherwise (with `initial` as its value).
+   */
+  fn declare_var(&mut self, name: &Symbol, initial: String, width: Width) -> Option<Var> {
+    match &mut self.frame {
+      Some(frame) => {
+        self.stab.not_const(name)?;
+        frame.alloc(name, 4, width).map(|offset| Var::Local(offset, width))
+      },
+      None => {
+        let data = format!("{} {}", width.directive(), initial);
+        self.stab.decl(*name, data, width.bytes(), width)?;
+        self.get_var(name)
+      },
+    }
+  }
+
+  /// declares a struct variable, which starts out as all zeros. `zeroed` if
+  /// it's a global that only gets declared once, so .data can do that.
+  fn declare_struct(&mut self, b: &mut IBlock, name: &Symbol, ty: &Symbol, zeroed: bool) {
+    // an unknown struct was reported by the type checker
+    let (size, align) = self.structs.get(ty).copied().unwrap_or((4, 4));
+    match &mut self.frame {
+      Some(frame) => {
+        // reported by the type checker
+        if size > 1024 {
+          return;
+        }
+        if self.stab.not_const(name).is_none() {
+          return;
+        }
+        if let Some(offset) = frame.alloc(name, size as i32, Width::Word) {
+          let (base, start) = self.local(b, offset);
+          for i in (0..size as i32).step_by(4) {
+            b.push(Instr::Store("sw", ZERO, start + i, base));
+          }
+          self.free_base(base);
+        }
+      },
+      None => {
+        if self.stab.decl(*name, format!(".space {}", size), align, Width::Word).is_none() || zeroed {
+          return;
+        }
+        // one in a block might run more than once, so it's cleared every time
+        let label = match self.get_var(name) {
+          Some(Var::Global(label, _)) => label,
+          _ => return,
+        };
+        let base = self.regs.get_reg().expect("failed to get register for struct address");
+        b.push(Instr::La(base, label));
+        let width = match align {
+          1 => Width::U8,
+          2 => Width::U16,
+          _ => Width::Word,
+        };
+        for i in (0..size).step_by(align as usize) {
+          b.push(Instr::Store(width.store(), ZERO, i as i32, base));
+        }
+        self.regs.free_reg(base);
+      },
+    }
+  }
+
+  fn get_var(&self, name: &Symbol) -> Option<Var> {
+    if let Some((offset, width)) = self.frame.as_ref().and_then(|f| f.get_var(name)) {
+      return Some(Var::Local(offset, width));
+    }
+    self.stab.get_var(name).map(|(l, width)| Var::Global(l.to_string(), *width))
+  }
+
+  fn store_var(&mut self, b: &mut IBlock, value: Reg, var: Var) {
+    match var {
+      Var::Global(label, width) => {
+        let addr_reg = self.regs.get_reg().expect("unable to allocate register to hold address in assignment");
+        b.push(Instr::StoreSym(width.store(), value, label, addr_reg));
+        self.regs.free_reg(addr_reg);
+      },
+      Var::Local(offset, width) => {
+        let (base, offset) = self.local(b, offset);
+        b.push(Instr::Store(width.store(), value, offset, base));
+        self.free_base(base);
+      },
+    }
   }
 
   fn compile_stmt(&mut self, b: &mut IBlock, s: &Stmt) {
@@ -229,14 +876,62 @@ impl Compiler {
             // FIXME: this probably leaks registers...
           }
         },
-        Stmt::Decl(name, init_val) => {
-          let val = match init_val {
-            crate::expr::DeclInit::Str(contents) => {
-              self.stab.add_string(contents.clone())
-            },
-            crate::expr::DeclInit::Int(val) => format!("{}", val),
+        Stmt::Decl(name, ty, init) => {
+          let width = ty.as_ref().map_or(Width::Word, |t| t.width());
+          // globals whose value is known up front start out with it in
+          // .data. Everything else (locals, anything in a block or inlined,
+          // which might run more than once) gets it stored when the `let` runs.
+          let global = self.frame.is_none() && self.inline_depth == 0 && self.stab.scopes.len() == 1;
+          if let Some(Type::Struct(ty)) = ty {
+            self.declare_struct(b, name, ty, global);
+            return;
+          }
+          let initial = match init {
+            Expr::String(contents) if global => Some(self.stab.add_string(contents.clone())),
+            e if global => const_eval(e).map(|v| v.to_string()),
+            _ => None,
           };
-          self.stab.decl(name.clone() /* PERF: avoid clone */, val);
+          if let Some(initial) = initial {
+            self.declare_var(name, initial, width);
+            return;
+          }
+
+          // the value is worked out before the variable exists, so in
+          // `let x = x + 1;` the x on the right is whatever x was before.
+          let value = match self.compile_expr(b, init) {
+            Some(r) => r,
+            None => return,
+          };
+          if let Some(var) = self.declare_var(name, "0".to_string(), width) {
+            self.store_var(b, value, var);
+          }
+          self.regs.free_reg(value);
+        },
+        Stmt::Store(addr, value, width) => {
+          let addr = match self.compile_expr(b, addr) {
+            Some(r) => r,
+            None => return,
+          };
+          if let Some(value) = self.compile_expr(b, value) {
+            b.push(Instr::Store(width.store(), value, 0, addr));
+            self.regs.free_reg(value);
+          }
+          self.regs.free_reg(addr);
+        },
+        Stmt::SetField(target, value) => {
+          let width = match target {
+            Expr::Field(_, m) => m.width,
+            _ => unreachable!(),
+          };
+          let (base, offset) = match self.compile_place(b, target) {
+            Some(p) => p,
+            None => return,
+          };
+          if let Some(value) = self.compile_expr(b, value) {
+            b.push(Instr::Store(width.store(), value, offset, base));
+            self.regs.free_reg(value);
+          }
+          self.free_base(base);
         },
         Stmt::Assignment(name, value) => {
           let result = if let Some(result_reg) = self.compile_expr(b, value) {
@@ -244,37 +939,23 @@ impl Compiler {
           } else {
             return;
           };
-          let var_label = match self.stab.get_var(&name) {
-            Some(l) => l.to_string(), // helps with ownership trouble
+          // a variable that doesn't exist was reported by the type checker
+          let var = match self.get_var(name) {
+            Some(v) => v,
             None => {
-              eprintln!("variable not found: {}", name);
+              self.regs.free_reg(result);
               return;
             },
           };
 
-          let addr_reg = match self.regs.get_reg() {
-            Some(r) => r,
-            None => {
-              eprintln!("unable to allocate register to hold address for {} in assignment", name);
-              return;
-            }
-          };
-
-          b.push(format!("sw {}, {}, {}", result, var_label, addr_reg));
+          self.store_var(b, result, var);
           self.regs.free_reg(result);
-          self.regs.free_reg(addr_reg);
         },
         Stmt::If(cond, true_block, false_block) => {
-          let cond_result = self.compile_expr(b, cond);
-          
           // use these later
           let false_label = self.label_counter.next();
-          // if the condition failed to compile, always assume false so that the rest of the
-          // if can still be compiled.
-          b.push(format!("beqz {}, {}", cond_result.unwrap_or(ZERO), false_label));
-          
-          cond_result.map(|r| self.regs.free_reg(r)); // the condition isnt used in the body, so free it now.
-          
+          self.compile_branch(b, cond, false, &false_label);
+
           let mut true_iblock = vec![];
           self.compile_block(&mut true_iblock, true_block);
           
@@ -298,37 +979,686 @@ impl Compiler {
             // - append in the else code
             // - add the end label used by the "true" block
             let end_label = self.label_counter.next();
-            b.push(format!("j {}", end_label));
-            b.push(format!("{}:", false_label));
+            b.push(Instr::Jump(end_label.clone()));
+            b.push(Instr::Label(false_label));
             b.append(&mut false_iblock);
-            b.push(format!("{}:", end_label));
+            b.push(Instr::Label(end_label));
           } else {
             // if there is no else block, all that needs to be done is to complete the jump by emitting a label.
-            b.push(format!("{}:", false_label));
+            b.push(Instr::Label(false_label));
           }
         },
-        Stmt::While(cond, body) => {
-          let top_lbl = self.label_counter.next();
-          b.push(format!("{}:", top_lbl));
-          let cond_result = self.compile_expr(b, cond);
-          
-          // use these later
-          let end_label = self.label_counter.next();
-          // if the condition failed to compile, always assume false so that the rest of the
-          // if can still be compiled.
-          b.push(format!("beqz {}, {}", cond_result.unwrap_or(ZERO), end_label));
-          
-          cond_result.map(|r| self.regs.free_reg(r)); // the condition isnt used in the body, so free it now.
-          
-          let mut body_iblock = vec![];
-          self.compile_block(&mut body_iblock, body);
-          
-          b.append(&mut body_iblock);
-          b.push(format!("j {}", top_lbl));
+        Stmt::Loop(l) => self.compile_loop(b, l),
+        Stmt::Match(value, arms) => self.compile_match(b, value, arms),
+        Stmt::Break(label) | Stmt::Continue(label) => {
+          let is_break = matches!(s, Stmt::Break(_));
+          let found = match label {
+            Some(name) => self.loops.iter().rev().find(|l| l.0.as_ref() == Some(name)),
+            None => self.loops.last(),
+          };
+          // one without a loop to go to was reported by the type checker
+          if let Some((_, brk, cont)) = found {
+            b.push(Instr::Jump(if is_break { brk } else { cont }.clone()));
+          }
+        },
+        // ones that aren't at the top level were reported by the type checker
+        Stmt::Fn(_) | Stmt::Struct(_) | Stmt::Const(..) | Stmt::Enum(..) => (),
+        Stmt::Return(value) => {
+          // so was a return outside of a function
+          let (label, dest) = match self.returns.last() {
+            Some(r) => r.clone(),
+            None => return,
+          };
+          match value.as_ref().map(|v| self.compile_expr(b, v)) {
+            Some(Some(r)) => {
+              b.push(Instr::Mv(dest, r));
+              self.regs.free_reg(r);
+            },
+            Some(None) => (), // error already reported
+            None => b.push(Instr::Li(dest, 0)),
+          }
+          b.push(Instr::Jump(label));
+        },
+        Stmt::Error => (), // reported by the parser
+    }
+  }
+
+  /**
+   * Where a variable, field or what a pointer points at is in memory: a
+   * register with an address in it, and an offset from there. That's `fp`
+   * for a local, which mustn't be freed, so use `free_base` for it.
+   */
+  fn compile_place(&mut self, b: &mut IBlock, e: &Expr) -> Option<(Reg, i32)> {
+    match e {
+      Expr::Ident(name) => match self.get_var(name) {
+        Some(Var::Local(offset, _)) => Some(self.local(b, offset)),
+        Some(Var::Global(label, _)) => {
+          let base = self.regs.get_reg().expect("failed to get register for struct address");
+          b.push(Instr::La(base, label));
+          Some((base, 0))
+        },
+        // reported by the type checker
+        None => None,
+      },
+      Expr::Unary(UnaryOp::Deref(_), addr) => Some((self.compile_expr(b, addr)?, 0)),
+      Expr::Field(inner, Member { offset, .. }) => {
+        let (base, outer) = self.compile_place(b, inner)?;
+        let offset = outer + offset;
+        if (-2048..2048).contains(&offset) {
+          return Some((base, offset));
+        }
+        // too far for a load or store to reach on its own
+        let r = if base == FP { self.regs.get_reg().expect("failed to get register for field address") } else { base };
+        let tmp = self.regs.get_reg().expect("failed to get register for field offset");
+        self.add_imm(b, r, base, offset as i64, tmp);
+        self.regs.free_reg(tmp);
+        Some((r, 0))
+      },
+      _ => {
+        eprintln!("ERR: can only get to the fields of a variable, or through a pointer");
+        None
+      },
+    }
+  }
+
+  /**
+   * Where the local at `offset` from `fp` is, for a load or store: `fp` and
+   * `offset`, unless it's too far away for that in a big frame, then a new
+   * register with its address in it. Free it with `free_base`.
+   */
+  fn local(&mut self, b: &mut IBlock, offset: i32) -> (Reg, i32) {
+    if (-2048..2048).contains(&offset) {
+      return (FP, offset);
+    }
+    let r = self.regs.get_reg().expect("failed to get register for local address");
+    self.add_imm(b, r, FP, offset as i64, r);
+    (r, 0)
+  }
+
+  fn free_base(&mut self, base: Reg) {
+    if base != FP {
+      self.regs.free_reg(base);
+    }
+  }
+
+  /**
+   * Compiles the body of an inlined function right where it was called. The
+   * arguments are evaluated and stored into the (renamed) parameters, then
+   * `return` jumps to the end with its value in the result register.
+   */
+  fn compile_inline(&mut self, b: &mut IBlock, inl: &Inlined) -> Option<Reg> {
+    // the body gets all of the temporaries to itself, like it would if it
+    // were called, so inlining never runs out of registers where a call wouldn't.
+    let result = self.regs.get_reg().expect("failed to get register for call result");
+    let live = self.save_temps(b, Some(result));
+    live.iter().for_each(|r| self.regs.free_reg(*r));
+
+    // each argument is stored as soon as it's worked out, so that only one
+    // of them is ever in a temporary. The parameters were renamed, so the
+    // later arguments can't see them.
+    self.push_scope();
+    self.inline_depth += 1;
+    let mut ok = true;
+    for (name, width, e) in &inl.params {
+      match self.compile_expr(b, e) {
+        Some(r) => {
+          if let Some(var) = self.declare_var(name, "0".to_string(), *width) {
+            self.store_var(b, r, var);
+          }
+          self.regs.free_reg(r);
+        },
+        None => ok = false,
+      }
+    }
+    if !ok {
+      eprintln!("ERR: failed to compile call to {}", inl.name);
+      self.inline_depth -= 1;
+      self.pop_scope();
+    }
+
+    if ok {
+      let end_label = self.label_counter.next();
+      self.returns.push((end_label.clone(), result));
+      // a break in the body can't get out to a loop around the call
+      let loops = std::mem::take(&mut self.loops);
+      self.compile_block(b, &inl.body);
+      self.loops = loops;
+      if !matches!(inl.body.last().map(|s| &s.node), Some(Stmt::Return(_))) {
+        b.push(Instr::Li(result, 0));
+      }
+      b.push(Instr::Label(end_label));
+      self.returns.pop();
+
+      self.inline_depth -= 1;
+      self.pop_scope();
+    }
+
+    for r in &live {
+      self.regs.map[r.0 as usize] = RState::Used;
+    }
+    self.restore_temps(b, &live);
+    if ok {
+      Some(result)
+    } else {
+      self.regs.free_reg(result);
+      None
+    }
+  }
+
+  /**
+   * Compiles any kind of loop. Like C compilers do, the condition is checked
+   * at the bottom so each iteration only takes one branch instead of a beqz
+   * and a j:
+   * ```text
+   *     <init>
+   *     j cond
+   *   top:
+   *     <body>
+   *   continue:
+   *     <step>
+   *   cond:
+   *     <branch to top if the condition holds>
+   *   break:
+   * ```
+   * Without a condition, `j cond` is left out and the branch is a `j top`.
+   */
+  fn compile_loop(&mut self, b: &mut IBlock, l: &Loop) {
+    // anything `init` declares is only visible inside the loop
+    self.push_scope();
+    l.init.iter().for_each(|s| self.compile_stmt(b, &s.node));
+
+    let top_lbl = self.label_counter.next();
+    let cond_lbl = self.label_counter.next();
+    let break_lbl = self.label_counter.next();
+    let continue_lbl = if l.step.is_empty() { cond_lbl.clone() } else { self.label_counter.next() };
+    if l.cond.is_some() {
+      b.push(Instr::Jump(cond_lbl.clone()));
+    }
+    b.push(Instr::Label(top_lbl.clone()));
+
+    self.loops.push((l.label, break_lbl.clone(), continue_lbl.clone()));
+    self.compile_block(b, &l.body);
+    self.loops.pop();
+
+    if !l.step.is_empty() {
+      b.push(Instr::Label(continue_lbl));
+      self.compile_block(b, &l.step);
+    }
+    b.push(Instr::Label(cond_lbl));
+    match &l.cond {
+      Some(cond) => self.compile_branch(b, cond, true, &top_lbl),
+      None => b.push(Instr::Jump(top_lbl)),
+    }
+    b.push(Instr::Label(break_lbl));
+    self.pop_scope();
+  }
+
+  /**
+   * Compiles a `match`: code that jumps to the right arm, then each arm's
+   * body one after the other. Which arm to jump to is looked up in a jump
+   * table when the values are close together, and found with a binary search
+   * otherwise.
+   */
+  fn compile_match(&mut self, b: &mut IBlock, value: &Expr, arms: &[Arm]) {
+    let value = match self.compile_expr(b, value) {
+      Some(r) => r,
+      None => return,
+    };
+    // nothing after a _ can ever run
+    let last = arms.iter().position(|a| a.patterns.contains(&Pattern::Wildcard));
+    if last.is_some_and(|i| i + 1 < arms.len()) {
+      eprintln!("WARN: match arms after a _ pattern are never used");
+    }
+    let arms = &arms[..last.map_or(arms.len(), |i| i + 1)];
+
+    let end = self.label_counter.next();
+    let labels: Vec<String> = arms.iter().map(|_| self.label_counter.next()).collect();
+    let default = match last {
+      Some(i) => labels[i].clone(),
+      None => end.clone(),
+    };
+    let cases = match_cases(arms);
+
+    let (low, high) = match (cases.first(), cases.last()) {
+      (Some(first), Some(last)) => (first.0, last.1),
+      _ => (0, -1),
+    };
+    let span = high - low + 1;
+    let covered: i64 = cases.iter().map(|(l, h, _)| h - l + 1).sum();
+    if cases.len() >= JUMP_TABLE_MIN_CASES && span <= JUMP_TABLE_MAX_SIZE && 2 * covered >= span {
+      // index = value - low, anything outside of 0..span (which is also
+      // anything below low, as an unsigned number) goes to the default.
+      let index = self.regs.get_reg().expect("failed to get register for match");
+      let tmp = self.regs.get_reg().expect("failed to get register for match");
+      self.add_imm(b, index, value, -low, tmp);
+      b.push(Instr::Li(tmp, span));
+      b.push(Instr::Branch("bgeu", index, tmp, default.clone()));
+      let table = self.label_counter.next();
+      b.push(Instr::OpImm("slli", index, index, 2));
+      b.push(Instr::La(tmp, table.clone()));
+      b.push(Instr::Op("add", index, index, tmp));
+      b.push(Instr::Load("lw", index, 0, index));
+
+      let entries: Vec<String> = (low..=high).map(|v| {
+        match cases.iter().find(|(l, h, _)| (*l..=*h).contains(&v)) {
+          Some((_, _, arm)) => labels[*arm].clone(),
+          None => default.clone(),
+        }
+      }).collect();
+      let mut targets = entries.clone();
+      targets.sort();
+      targets.dedup();
+      b.push(Instr::JumpTable(index, targets));
+      self.stab.tables.push((table, entries));
+      self.regs.free_reg(index);
+      self.regs.free_reg(tmp);
+    } else {
+      self.match_search(b, value, &cases, &labels, &default);
+    }
+    self.regs.free_reg(value);
 
-          b.push(format!("{}:", end_label));
+    for (arm, label) in arms.iter().zip(labels) {
+      b.push(Instr::Label(label));
+      self.compile_block(b, &arm.body);
+      b.push(Instr::Jump(end.clone()));
+    }
+    b.push(Instr::Label(end));
+  }
+
+  /**
+   * Jumps to the arm for `value` out of `cases` (sorted, as from
+   * `match_cases`), or to `default` if there isn't one. The cases are split
+   * in half with a single comparison until there are only a few left, which
+   * are then checked one at a time.
+   */
+  fn match_search(&mut self, b: &mut IBlock, value: Reg, cases: &[(i64, i64, usize)], labels: &[String], default: &str) {
+    let tmp = self.regs.get_reg().expect("failed to get register for match");
+    if cases.len() > 3 {
+      let mid = cases.len() / 2;
+      let lower = self.label_counter.next();
+      b.push(Instr::Li(tmp, cases[mid].0));
+      b.push(Instr::Branch("blt", value, tmp, lower.clone()));
+      self.regs.free_reg(tmp);
+      self.match_search(b, value, &cases[mid..], labels, default);
+      b.push(Instr::Label(lower));
+      self.match_search(b, value, &cases[..mid], labels, default);
+      return;
+    }
+
+    for &(low, high, arm) in cases {
+      if low == high {
+        b.push(Instr::Li(tmp, low));
+        b.push(Instr::Branch("beq", value, tmp, labels[arm].clone()));
+      } else {
+        // low <= value <= high is the same as value - low <= high - low, unsigned
+        let diff = self.regs.get_reg().expect("failed to get register for match");
+        self.add_imm(b, diff, value, -low, tmp);
+        b.push(Instr::Li(tmp, high - low));
+        b.push(Instr::Branch("bgeu", tmp, diff, labels[arm].clone()));
+        self.regs.free_reg(diff);
+      }
+    }
+    b.push(Instr::Jump(default.to_string()));
+    self.regs.free_reg(tmp);
+  }
+
+  /// rd = rs + imm, using `tmp` if imm doesn't fit in an addi.
+  fn add_imm(&mut self, b: &mut IBlock, rd: Reg, rs: Reg, imm: i64, tmp: Reg) {
+    if (-2048..2048).contains(&imm) {
+      b.push(Instr::OpImm("addi", rd, rs, imm));
+    } else {
+      b.push(Instr::Li(tmp, imm));
+      b.push(Instr::Op("add", rd, rs, tmp));
+    }
+  }
+
+  /**
+   * Emits a jump to `target` that is taken when `cond` is `when` (nonzero
+   * counts as true). Comparisons at the top of the condition become a single
+   * compare-and-branch instead of an slt/seqz followed by a beqz, and `!`
+   * just flips which way the branch goes.
+   */
+  fn compile_branch(&mut self, b: &mut IBlock, cond: &Expr, when: bool, target: &str) {
+    use crate::expr::BinOp::*;
+
+    match cond {
+      Expr::Unary(UnaryOp::BoolNot, inner) => {
+        self.compile_branch(b, inner, !when, target);
+      },
+      Expr::Lit(val) => {
+        // constant condition, so either always jump or never jump.
+        if (*val != 0) == when {
+          b.push(Instr::Jump(target.to_string()));
         }
+      },
+      Expr::Bin(left, op @ (Less | LessUnsigned | Greater | TestEq), right) => {
+        let right = self.compile_operand(b, right); // same order as compile_expr
+        let left = self.compile_operand(b, left);
+        let (left, right) = match (left, right) {
+          (Some(l), Some(r)) => (l, r),
+          (l, r) => {
+            // if the condition failed to compile, always assume false so that the rest of the
+            // if can still be compiled.
+            if !when {
+              b.push(Instr::Jump(target.to_string()));
+            }
+            if let Some(l) = l { self.regs.free_reg(l) }
+            if let Some(r) = r { self.regs.free_reg(r) }
+            return;
+          }
+        };
+
+        // a > b is the same as b < a, so just swap the operands.
+        let (mnemonic, first, second) = match (op, when) {
+          (Less, true) => ("blt", left, right),
+          (Less, false) => ("bge", left, right),
+          (LessUnsigned, true) => ("bltu", left, right),
+          (LessUnsigned, false) => ("bgeu", left, right),
+          (Greater, true) => ("blt", right, left),
+          (Greater, false) => ("bge", right, left),
+          (TestEq, true) => ("beq", left, right),
+          (TestEq, false) => ("bne", left, right),
+          _ => unreachable!(),
+        };
+        b.push(Instr::Branch(mnemonic, first, second, target.to_string()));
+        self.regs.free_reg(left);
+        self.regs.free_reg(right);
+      },
+      _ => {
+        let cond_result = self.compile_expr(b, cond);
+        let mnemonic = if when { "bnez" } else { "beqz" };
+        // if the condition failed to compile, always assume false so that the rest of the
+        // if can still be compiled.
+        b.push(Instr::BranchZ(mnemonic, cond_result.unwrap_or(ZERO), target.to_string()));
+        if let Some(r) = cond_result { self.regs.free_reg(r) } // the condition isnt used after the branch, so free it now.
+      },
+    }
+  }
+
+  /**
+   * `if cond { then } else { otherwise }` as a value. When both sides are
+   * cheap, both get worked out and the condition turns into a mask that
+   * picks one of them, which saves a branch that could go either way.
+   * Otherwise only the side that's needed runs, like an if statement.
+   */
+  fn compile_select(&mut self, b: &mut IBlock, cond: &Expr, then: &Expr, otherwise: &Expr) -> Option<Reg> {
+    use crate::expr::BinOp::*;
+
+    if let Some(c) = const_eval(cond) {
+      return self.compile_expr(b, if c != 0 { then } else { otherwise });
+    }
+
+    let cheap = match (branchless_cost(then), branchless_cost(otherwise)) {
+      (Some(t), Some(f)) => t + f <= BRANCHLESS_MAX_COST,
+      _ => false,
+    };
+    if cheap {
+      let c = self.compile_expr(b, cond)?;
+      let is_bool = matches!(cond,
+        Expr::Bin(_, Less | LessUnsigned | Greater | TestEq, _) | Expr::Unary(UnaryOp::BoolNot, _));
+      if !is_bool {
+        b.push(Instr::Unary("snez", c, c));
+      }
+      // `c` is freed whether or not the arms compile
+      let result = match (then, otherwise) {
+        (_, Expr::Lit(0)) => {
+          // all ones when true, so the and keeps `then`
+          b.push(Instr::Op("sub", c, ZERO, c));
+          let t = self.compile_expr(b, then);
+          if let Some(t) = t {
+            b.push(Instr::Op("and", t, t, c));
+          }
+          t
+        },
+        (Expr::Lit(0), _) => {
+          // all ones when false instead
+          b.push(Instr::OpImm("addi", c, c, -1));
+          let f = self.compile_expr(b, otherwise);
+          if let Some(f) = f {
+            b.push(Instr::Op("and", f, f, c));
+          }
+          f
+        },
+        _ => {
+          // otherwise ^ ((then ^ otherwise) & mask)
+          b.push(Instr::Op("sub", c, ZERO, c));
+          match (self.compile_expr(b, then), self.compile_expr(b, otherwise)) {
+            (Some(t), Some(f)) => {
+              b.push(Instr::Op("xor", t, t, f));
+              b.push(Instr::Op("and", t, t, c));
+              b.push(Instr::Op("xor", t, t, f));
+              self.regs.free_reg(f);
+              Some(t)
+            },
+            (t, f) => {
+              t.into_iter().chain(f).for_each(|r| self.regs.free_reg(r));
+              None
+            },
+          }
+        },
+      };
+      self.regs.free_reg(c);
+      return result;
+    }
+
+    let result = self.regs.get_reg().expect("failed to allocate reg for conditional");
+    let else_label = self.label_counter.next();
+    let end_label = self.label_counter.next();
+    self.compile_branch(b, cond, false, &else_label);
+    if let Some(r) = self.compile_expr(b, then) {
+      b.push(Instr::Mv(result, r));
+      self.regs.free_reg(r);
+    }
+    b.push(Instr::Jump(end_label.clone()));
+    b.push(Instr::Label(else_label));
+    if let Some(r) = self.compile_expr(b, otherwise) {
+      b.push(Instr::Mv(result, r));
+      self.regs.free_reg(r);
+    }
+    b.push(Instr::Label(end_label));
+    Some(result)
+  }
+
+  /**
+   * Like compile_expr, but a literal 0 just uses x0 instead of taking up a
+   * register. Only use this where the result is read and never written.
+   */
+  fn compile_operand(&mut self, b: &mut IBlock, e: &Expr) -> Option<Reg> {
+    match e {
+      Expr::Lit(0) => Some(ZERO),
+      _ => self.compile_expr(b, e),
+    }
+  }
+
+  /**
+   * Calls a user function once its arguments are in place. It may use any of
+   * the temporaries, so the ones still holding something are saved on the
+   * stack around the call.
+   */
+  fn call_user_function(&mut self, b: &mut IBlock, name: &Symbol) {
+    let live = self.save_temps(b, None);
+    b.push(Instr::Call(mangle("__fn", name.as_str()), CALLER_SAVED));
+    self.restore_temps(b, &live);
+    self.wanted.push(*name);
+  }
+
+  /// pushes every temporary in use (other than `except`) onto the stack.
+  fn save_temps(&mut self, b: &mut IBlock, except: Option<Reg>) -> Vec<Reg> {
+    let live: Vec<Reg> = self.regs.in_use().into_iter().filter(|r| Some(*r) != except).collect();
+    let size = (4 * live.len() as i32 + 15) / 16 * 16;
+    if size > 0 {
+      b.push(Instr::OpImm("addi", SP, SP, -size as i64));
+    }
+    for (i, r) in live.iter().enumerate() {
+      b.push(Instr::Store("sw", *r, 4 * i as i32, SP));
+    }
+    live
+  }
+
+  /// pops what `save_temps` pushed.
+  fn restore_temps(&mut self, b: &mut IBlock, live: &[Reg]) {
+    let size = (4 * live.len() as i32 + 15) / 16 * 16;
+    for (i, r) in live.iter().enumerate() {
+      b.push(Instr::Load("lw", *r, 4 * i as i32, SP));
+    }
+    if size > 0 {
+      b.push(Instr::OpImm("addi", SP, SP, size as i64));
+    }
+  }
+
+  /**
+   * Cuts the value in `r` down to the range of `to`, sign or zero extending
+   * it back to 32 bits the way a load of that width would. Anything 32 bits
+   * wide is left as it is.
+   */
+  fn narrow(&mut self, b: &mut IBlock, r: Reg, to: &Type) {
+    let (shift, right) = match to {
+      Type::U8 => {
+        b.push(Instr::OpImm("andi", r, r, 255));
+        return;
+      },
+      Type::I8 => (24, "srai"),
+      Type::I16 => (16, "srai"),
+      Type::U16 => (16, "srli"),
+      _ => return,
+    };
+    b.push(Instr::OpImm("slli", r, r, shift));
+    b.push(Instr::OpImm(right, r, r, shift));
+  }
+
+  /**
+   * Calls one of the software multiply/divide routines in the standard
+   * library, for targets without the M extension. These take their operands
+   * in a0 and a1 and only change a0, so nothing else needs saving.
+   */
+  fn runtime_call(&mut self, b: &mut IBlock, routine: &str, left: Reg, right: Reg) -> Option<Reg> {
+    b.push(Instr::Mv(A0, left));
+    b.push(Instr::Mv(A1, right));
+    b.push(Instr::Call(routine.to_string(), RegSet::default().with(A0).with(A1).with(RA)));
+    b.push(Instr::Mv(left, A0));
+    self.regs.free_reg(right);
+    Some(left)
+  }
+
+  /**
+   * Strength reduction for multiplying, dividing or taking the remainder by
+   * a literal. Returns None if `op` isn't one of those or there is no
+   * literal, in which case the operation should be compiled normally.
+   */
+  fn compile_by_constant(&mut self, b: &mut IBlock, left: &Expr, op: BinOp, right: &Expr) -> Option<Option<Reg>> {
+    match (op, left, right) {
+      (BinOp::Mul, _, Expr::Lit(c)) => self.compile_mul_const(b, left, *c as i32),
+      (BinOp::Mul, Expr::Lit(c), _) => self.compile_mul_const(b, right, *c as i32),
+      (BinOp::Div, _, Expr::Lit(c)) => self.compile_div_const(b, left, *c as i32),
+      (BinOp::Rem, _, Expr::Lit(c)) => self.compile_rem_const(b, left, *c as i32),
+      _ => None,
+    }
+  }
+
+  fn compile_mul_const(&mut self, b: &mut IBlock, e: &Expr, c: i32) -> Option<Option<Reg>> {
+    let terms = signed_digits(c);
+    // with a hardware multiplier, only bother when it's a couple of shifts.
+    if self.target == Target::Rv32im && terms.len() > 2 {
+      return None;
+    }
+
+    let x = match self.compile_expr(b, e) {
+      Some(r) => r,
+      None => return Some(None),
+    };
+    if terms.is_empty() {
+      // the operand was still compiled in case it has side effects.
+      b.push(Instr::Li(x, 0));
+      return Some(Some(x));
+    }
+
+    // start from a positive term if there is one so it doesn't need negating.
+    let mut terms = terms;
+    terms.sort_by_key(|(sign, _)| -sign);
+
+    let acc = self.regs.get_reg().expect("failed to allocate reg for multiply");
+    let tmp = self.regs.get_reg().expect("failed to allocate reg for multiply");
+    let (first_sign, first_shift) = terms[0];
+    b.push(Instr::OpImm("slli", acc, x, first_shift as i64));
+    if first_sign < 0 {
+      b.push(Instr::Op("sub", acc, ZERO, acc));
+    }
+    for (sign, shift) in &terms[1..] {
+      b.push(Instr::OpImm("slli", tmp, x, *shift as i64));
+      b.push(Instr::Op(if *sign > 0 { "add" } else { "sub" }, acc, acc, tmp));
+    }
+    self.regs.free_reg(tmp);
+    self.regs.free_reg(x);
+    Some(Some(acc))
+  }
+
+  fn compile_div_const(&mut self, b: &mut IBlock, e: &Expr, d: i32) -> Option<Option<Reg>> {
+    let k = d.unsigned_abs().trailing_zeros();
+    if d == 0 || d.unsigned_abs() != 1 << k {
+      return None; // not a power of two, so it needs a real divide
+    }
+
+    let x = match self.compile_expr(b, e) {
+      Some(r) => r,
+      None => return Some(None),
+    };
+    if k > 0 {
+      // shifting alone rounds towards negative infinity, but division rounds
+      // towards zero. Adding 2^k - 1 first to negative numbers fixes that up.
+      let bias = self.round_towards_zero_bias(b, x, k);
+      b.push(Instr::Op("add", bias, x, bias));
+      b.push(Instr::OpImm("srai", x, bias, k as i64));
+      self.regs.free_reg(bias);
+    }
+    if d < 0 {
+      b.push(Instr::Op("sub", x, ZERO, x));
+    }
+    Some(Some(x))
+  }
+
+  fn compile_rem_const(&mut self, b: &mut IBlock, e: &Expr, d: i32) -> Option<Option<Reg>> {
+    // the sign of the divisor doesn't matter for the remainder.
+    let k = d.unsigned_abs().trailing_zeros();
+    if d == 0 || d.unsigned_abs() != 1 << k {
+      return None;
+    }
+
+    let x = match self.compile_expr(b, e) {
+      Some(r) => r,
+      None => return Some(None),
+    };
+    if k == 0 {
+      b.push(Instr::Li(x, 0));
+      return Some(Some(x));
     }
+    // the remainder has the sign of the dividend, so bias negative numbers
+    // like for division, mask off the low bits, then take the bias back out.
+    let bias = self.round_towards_zero_bias(b, x, k);
+    b.push(Instr::Op("add", x, x, bias));
+    let mask = (1i64 << k) - 1;
+    if mask < 2048 {
+      b.push(Instr::OpImm("andi", x, x, mask));
+    } else {
+      let m = self.regs.get_reg().expect("failed to allocate reg for remainder mask");
+      b.push(Instr::Li(m, mask));
+      b.push(Instr::Op("and", x, x, m));
+      self.regs.free_reg(m);
+    }
+    b.push(Instr::Op("sub", x, x, bias));
+    self.regs.free_reg(bias);
+    Some(Some(x))
+  }
+
+  /// 2^k - 1 if `x` is negative, 0 otherwise, in a new register.
+  fn round_towards_zero_bias(&mut self, b: &mut IBlock, x: Reg, k: u32) -> Reg {
+    let bias = self.regs.get_reg().expect("failed to allocate reg for division");
+    if k == 1 {
+      b.push(Instr::OpImm("srli", bias, x, 31));
+    } else {
+      b.push(Instr::OpImm("srai", bias, x, 31));
+      b.push(Instr::OpImm("srli", bias, bias, 32 - k as i64));
+    }
+    bias
   }
 
   pub fn compile_expr(&mut self, b: &mut IBlock, e: &Expr) -> Option<Reg> {
@@ -338,19 +1668,23 @@ impl Compiler {
         if *val > u32::MAX as _ || *val < i32::MIN as _ {
           eprintln!("WARN: immediate {} is out of 32 bit range", val);
         }
-        b.push(format!("li {}, {}", reg, val));
+        b.push(Instr::Li(reg, *val));
         Some(reg)
       },
       Expr::Bin(left, op, right) =>{
         use crate::expr::BinOp::*;
 
-        let right = self.compile_expr(b, &right); // compiling right first helps with register management
-        let left = self.compile_expr(b, &left);
+        if let Some(result) = self.compile_by_constant(b, left, *op, right) {
+          return result;
+        }
+
+        let right = self.compile_expr(b, right); // compiling right first helps with register management
+        let left = self.compile_expr(b, left);
         let left = left?;
         let right = right?;
 
         fn simple(this: &mut Compiler, b: &mut IBlock, mnemonic: &'static str, left: Reg, right: Reg) -> Option<Reg> {
-          b.push(format!("{} {}, {}, {}", mnemonic, left, left, right));
+          b.push(Instr::Op(mnemonic, left, left, right));
           this.regs.free_reg(right);
           Some(left)
         }
@@ -362,6 +1696,21 @@ impl Compiler {
           Sub => {
             simple(self, b, "sub", left, right)
           },
+          Mul if self.target == Target::Rv32i => {
+            self.runtime_call(b, "__mulsi3", left, right)
+          },
+          Div if self.target == Target::Rv32i => {
+            self.runtime_call(b, "__divsi3", left, right)
+          },
+          Rem if self.target == Target::Rv32i => {
+            self.runtime_call(b, "__modsi3", left, right)
+          },
+          DivUnsigned if self.target == Target::Rv32i => {
+            self.runtime_call(b, "__udivsi3", left, right)
+          },
+          RemUnsigned if self.target == Target::Rv32i => {
+            self.runtime_call(b, "__umodsi3", left, right)
+          },
           Mul => {
             simple(self, b, "mul", left, right)
           },
@@ -370,7 +1719,13 @@ impl Compiler {
           },
           Rem => {
             simple(self, b, "rem", left, right)
-          }
+          },
+          DivUnsigned => {
+            simple(self, b, "divu", left, right)
+          },
+          RemUnsigned => {
+            simple(self, b, "remu", left, right)
+          },
           Srl => {
             // todo: implement immediate versions
             simple(self, b, "srl", left, right)
@@ -405,24 +1760,19 @@ impl Compiler {
           },
           Greater => {
             // todo: implement immediate versions
-            simple(self, b, "sltu", right, left)
+            simple(self, b, "slt", right, left)
           },
           TestEq => {
-            b.push(format!("xor {}, {}, {}", left, left, right));
-            b.push(format!("seqz {}, {}", left, left));
+            b.push(Instr::Op("xor", left, left, right));
+            b.push(Instr::Unary("seqz", left, left));
             self.regs.free_reg(right);
             Some(left)
           },
         }
       }
       Expr::Ident(name) => {
-        let label = match self.stab.get_var(name) {
-          Some(l) => l.to_string(), // helps with ownership trouble
-          None => {
-            eprintln!("variable not found: {}", name);
-            return None;
-          }
-        };
+        // a variable that doesn't exist was reported by the type checker
+        let var = self.get_var(name)?;
         let r = match self.regs.get_reg() {
           Some(r) => r,
           None => {
@@ -430,44 +1780,113 @@ impl Compiler {
             return None;
           }
         };
-        b.push(format!("lw {}, {}", r, label));
+        match var {
+          Var::Global(label, width) => b.push(Instr::LoadSym(width.load(), r, label)),
+          Var::Local(offset, width) => {
+            let (base, offset) = self.local(b, offset);
+            b.push(Instr::Load(width.load(), r, offset, base));
+            self.free_base(base);
+          },
+        }
         Some(r)
       },
       Expr::Call(name, params) => {
+        let user = self.functions.contains_key(name);
+        // unknown functions and the wrong number of arguments were reported
+        // by the type checker
+        let arity = match self.functions.get(name) {
+          Some(f) => f.params.len(),
+          None => crate::stdlib::signature(name.as_str())?.params.len(),
+        };
+        if arity != params.len() {
+          return None;
+        }
+
+        // arguments that call something could overwrite the a registers, so
+        // they're worked out first: all but the last onto the stack, and the
+        // last one into a temporary. The others go straight into their a
+        // register, so there's never more than one of them in a temporary.
+        let calling: Vec<usize> = (0..params.len()).filter(|&i| calls(&params[i], self.target)).collect();
+        let spilled = &calling[..calling.len().saturating_sub(1)];
+        let size = (4 * spilled.len() as i32 + 15) / 16 * 16;
+        if size > 0 {
+          b.push(Instr::OpImm("addi", SP, SP, -size as i64));
+        }
         let mut all_ok = true;
-        for (i, param_expr) in params.iter().enumerate() {
-          if let Some(r) = self.compile_expr(b, param_expr) {
-            b.push(format!("mv a{}, {}", i, r));
-            self.regs.free_reg(r);
-          } else {
-            all_ok = false;
+        let mut held = None;
+        for (slot, &i) in calling.iter().enumerate() {
+          match self.compile_expr(b, &params[i]) {
+            Some(r) if slot < spilled.len() => {
+              b.push(Instr::Store("sw", r, 4 * slot as i32, SP));
+              self.regs.free_reg(r);
+            },
+            Some(r) => held = Some((i, r)),
+            None => all_ok = false,
           }
         }
+        for (i, p) in params.iter().enumerate().filter(|(i, _)| !calling.contains(i)) {
+          match self.compile_expr(b, p) {
+            Some(r) => {
+              b.push(Instr::Mv(arg_reg(i), r));
+              self.regs.free_reg(r);
+            },
+            None => all_ok = false,
+          }
+        }
+        if let Some((i, r)) = held {
+          b.push(Instr::Mv(arg_reg(i), r));
+          self.regs.free_reg(r);
+        }
+        for (slot, &i) in spilled.iter().enumerate() {
+          b.push(Instr::Load("lw", arg_reg(i), 4 * slot as i32, SP));
+        }
+        if size > 0 {
+          b.push(Instr::OpImm("addi", SP, SP, size as i64));
+        }
 
-        if all_ok { // if the args are invalid, dont compile the call i guess.
-          b.push(format!("call {}", name));
-        } else {
+        if !all_ok { // if the args are invalid, dont compile the call i guess.
           eprintln!("ERR: failed to compile call to {}", name);
+        } else if user {
+          self.call_user_function(b, name);
+        } else {
+          // the standard library only touches the argument registers.
+          b.push(Instr::Call(name.to_string(), ARG_REGS.with(RA)));
         }
 
-        Some(A0)
+        // copy the result out of a0 so that another call can't overwrite it.
+        let reg = self.regs.get_reg().expect("failed to get register for call result");
+        b.push(Instr::Mv(reg, A0));
+        Some(reg)
+      },
+      Expr::Inline(inl) => self.compile_inline(b, inl),
+      Expr::Cond(cond, then, otherwise) => self.compile_select(b, cond, then, otherwise),
+      Expr::Cast(e, to) => {
+        let r = self.compile_expr(b, e)?;
+        self.narrow(b, r, to);
+        Some(r)
+      },
+      Expr::Field(_, m) => {
+        let (base, offset) = self.compile_place(b, e)?;
+        let r = if base == FP { self.regs.get_reg().expect("failed to get register for field") } else { base };
+        b.push(Instr::Load(m.width.load(), r, offset, base));
+        Some(r)
       },
       Expr::String(s) => {
         let lbl = self.stab.add_string(s.clone());
         let reg = self.regs.get_reg().expect("failed to get register for string");
-        b.push(format!("la {}, {}", reg, lbl));
+        b.push(Instr::La(reg, lbl));
         Some(reg)
       },
       Expr::Unary(operator, operand) => {
         match operator {
-          UnaryOp::Deref => {
+          UnaryOp::Deref(width) => {
             let operand_result = self.compile_expr(b, operand)?;
-            b.push(format!("lw {}, ({})", operand_result, operand_result));
+            b.push(Instr::Load(width.load(), operand_result, 0, operand_result));
             Some(operand_result)
           },
           UnaryOp::BoolNot => {
             let operand_result = self.compile_expr(b, operand)?;
-            b.push(format!("seqz {}, {}", operand_result, operand_result));
+            b.push(Instr::Unary("seqz", operand_result, operand_result));
             Some(operand_result)
           },
           UnaryOp::Addr => {
@@ -481,17 +1900,23 @@ impl Compiler {
                 None
               },
               Expr::Ident(name) => {
+                // reported by the type checker if it doesn't exist
+                let var = self.get_var(name)?;
                 let reg = self.regs.get_reg().expect("failed to get register for addressof temporary");
-                let label = match self.stab.get_var(&name) {
-                  Some(l) => l,
-                  None => {
-                    eprintln!("ERR: variable not found: {}", name);
-                    return None;
-                  },
-                };
-                b.push(format!("la {}, {}", reg, label));
+                match var {
+                  Var::Global(label, _) => b.push(Instr::La(reg, label)),
+                  Var::Local(offset, _) => self.add_imm(b, reg, FP, offset as i64, reg),
+                }
                 Some(reg)
               },
+              Expr::Field(..) => {
+                let (base, offset) = self.compile_place(b, operand)?;
+                let r = if base == FP { self.regs.get_reg().expect("failed to get register for addressof temporary") } else { base };
+                if r != base || offset != 0 {
+                  b.push(Instr::OpImm("addi", r, base, offset as i64));
+                }
+                Some(r)
+              },
               _ => {
                 eprintln!("ERR: cannot take address of a temporary value");
                 None
@@ -500,12 +1925,12 @@ impl Compiler {
           },
           UnaryOp::Neg => {
             let operand_result = self.compile_expr(b, operand)?;
-            b.push(format!("sub {}, x0, {}", operand_result, operand_result));
+            b.push(Instr::Op("sub", operand_result, ZERO, operand_result));
             Some(operand_result)
           },
           UnaryOp::Not => {
             let operand_result = self.compile_expr(b, operand)?;
-            b.push(format!("xori {}, {}, -1", operand_result, operand_result));
+            b.push(Instr::OpImm("xori", operand_result, operand_result, -1));
             Some(operand_result)
           },
         }
@@ -513,3 +1938,24 @@ impl Compiler {
     }
   }
 }
+
+/**
+ * Writes `c` as a sum of signed powers of two, as (sign, shift) pairs, using
+ * as few terms as possible (the non-adjacent form). Multiplying by `c` is then
+ * just shifting and adding or subtracting.
+ */
+fn signed_digits(c: i32) -> Vec<(i32, u32)> {
+  let mut digits = vec![];
+  let mut n = c as i64;
+  let mut shift = 0;
+  while n != 0 && shift < 32 {
+    if n & 1 != 0 {
+      let d = 2 - n.rem_euclid(4); // 1 or -1
+      digits.push((d as i32, shift));
+      n -= d;
+    }
+    n >>= 1;
+    shift += 1;
+  }
+  digits
+}
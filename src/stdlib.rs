@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+
+const SOURCE: &str = include_str!("../resources/stdlib.s");
+
+/// One routine from `resources/stdlib.s`.
+pub struct Routine {
+  pub name: &'static str,
+  /// other routines this one calls
+  pub needs: Vec<&'static str>,
+  /// the assembly, including the `# unit:` line
+  pub code: String,
+  /// the ecall number, if all the routine does is `li a7, N; ecall; ret`
+  pub ecall: Option<i64>,
+}
+
+/// every routine in the standard library, in the order they're in the file.
+pub fn routines() -> Vec<Routine> {
+  let mut units: Vec<Routine> = vec![];
+  for line in SOURCE.lines() {
+    if let Some(name) = line.strip_prefix("# unit:") {
+      units.push(Routine { name: name.trim(), needs: vec![], code: String::new(), ecall: None });
+    }
+    let unit = match units.last_mut() {
+      Some(u) => u,
+      None => continue, // the comment at the top of the file
+    };
+    if let Some(needs) = line.strip_prefix("# needs:") {
+      unit.needs.extend(needs.split_whitespace());
+    }
+    unit.code.push_str(line);
+    unit.code.push('\n');
+  }
+  for unit in &mut units {
+    unit.ecall = ecall_number(&unit.code);
+  }
+  units
+}
+
+/// what a parameter or return value is supposed to be
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+  /// nothing is returned
+  Void,
+  Int,
+  /// an ascii character
+  Char,
+  /// the address of a zero-terminated string
+  Str,
+  /// the address of a buffer to read into
+  Addr,
+  /// a file descriptor
+  Fd,
+}
+
+impl Kind {
+  pub fn name(self) -> &'static str {
+    match self {
+      Kind::Void => "void",
+      Kind::Int => "int",
+      Kind::Char => "char",
+      Kind::Str => "str",
+      Kind::Addr => "addr",
+      Kind::Fd => "fd",
+    }
+  }
+}
+
+/// How to call a standard library routine, and what it does.
+pub struct Signature {
+  pub name: &'static str,
+  pub params: &'static [(&'static str, Kind)],
+  pub ret: Kind,
+  /// the RARS ecall it wraps, if any
+  pub ecall: Option<u32>,
+  pub doc: &'static str,
+}
+
+macro_rules! sig {
+  ($name:ident($($p:ident: $k:ident),*) -> $ret:ident, $ecall:expr, $doc:expr) => {
+    Signature { name: stringify!($name), params: &[$((stringify!($p), Kind::$k)),*], ret: Kind::$ret, ecall: $ecall, doc: $doc }
+  };
+}
+
+/// every routine in `resources/stdlib.s`.
+pub const SIGNATURES: &[Signature] = &[
+  sig!(print_dec(n: Int) -> Void, Some(1), "Prints `n` in decimal."),
+  sig!(print_str(s: Str) -> Void, Some(4), "Prints the string at `s`."),
+  sig!(read_int() -> Int, Some(5), "Reads an integer from the console."),
+  sig!(read_string(buf: Addr, len: Int) -> Void, Some(8),
+    "Reads a line of at most `len - 1` characters into `buf`, and zero-terminates it."),
+  sig!(sbrk(bytes: Int) -> Addr, Some(9), "Allocates `bytes` bytes on the heap and returns their address."),
+  sig!(exit() -> Void, Some(10), "Stops the program."),
+  sig!(print_char(c: Char) -> Void, Some(11), "Prints the character `c`."),
+  sig!(read_char() -> Char, Some(12), "Reads a character from the console."),
+  sig!(get_cwd(buf: Addr, len: Int) -> Int, Some(17),
+    "Writes the current working directory into `buf`. Returns -1 if it doesn't fit in `len` bytes."),
+  sig!(get_time() -> Int, Some(30), "Returns the low 32 bits of the time in milliseconds since 1970."),
+  sig!(sleep(ms: Int) -> Void, Some(32), "Waits for `ms` milliseconds."),
+  sig!(print_hex(n: Int) -> Void, Some(34), "Prints `n` in hexadecimal."),
+  sig!(print_bin(n: Int) -> Void, Some(35), "Prints `n` in binary."),
+  sig!(print_unsigned(n: Int) -> Void, Some(36), "Prints `n` in decimal, as an unsigned number."),
+  sig!(rand_seed(id: Int, seed: Int) -> Void, Some(40), "Seeds random number generator `id`."),
+  sig!(rand_int(id: Int) -> Int, Some(41), "Returns a random number from generator `id`."),
+  sig!(rand_int_range(id: Int, upper: Int) -> Int, Some(42),
+    "Returns a random number from generator `id` that is at least 0 and less than `upper`."),
+  sig!(confirm_dialog(message: Str) -> Int, Some(50),
+    "Shows a yes/no/cancel dialog. Returns 0 for yes, 1 for no and 2 for cancel."),
+  sig!(close(fd: Fd) -> Void, Some(57), "Closes a file."),
+  sig!(read_fd(fd: Fd, buf: Addr, len: Int) -> Int, Some(63),
+    "Reads up to `len` bytes from a file into `buf`. Returns how many were read, or -1 on error."),
+  sig!(write_fd(fd: Fd, buf: Addr, len: Int) -> Int, Some(64),
+    "Writes `len` bytes from `buf` to a file. Returns how many were written, or -1 on error."),
+  sig!(exit2(code: Int) -> Void, Some(93), "Stops the program with exit code `code`."),
+  sig!(open(path: Str, flags: Int) -> Fd, Some(1024),
+    "Opens a file for reading (`flags` 0), writing (1) or appending (9). Returns -1 on error."),
+  sig!(__mulsi3(a: Int, b: Int) -> Int, None, "Returns `a * b`. Used for `*` on rv32i."),
+  sig!(__divsi3(a: Int, b: Int) -> Int, None, "Returns `a / b`. Used for `/` on rv32i."),
+  sig!(__modsi3(a: Int, b: Int) -> Int, None, "Returns `a % b`. Used for `%` on rv32i."),
+  sig!(__udivsi3(a: Int, b: Int) -> Int, None, "Returns `a / b` as unsigned numbers. Used for `/` on `u32` on rv32i."),
+  sig!(__umodsi3(a: Int, b: Int) -> Int, None, "Returns `a % b` as unsigned numbers. Used for `%` on `u32` on rv32i."),
+  sig!(__divmodsi4(a: Int, b: Int) -> Int, None, "Returns `a / b`, and leaves `a % b` in a1."),
+  sig!(__udivmodsi4(a: Int, b: Int) -> Int, None,
+    "Returns `a / b` as unsigned numbers, and leaves `a % b` in a1."),
+];
+
+pub fn signature(name: &str) -> Option<&'static Signature> {
+  SIGNATURES.iter().find(|s| s.name == name)
+}
+
+/**
+ * The closest of `candidates` to `name`, if any of them are close enough to
+ * be a typo.
+ */
+pub fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+  let max = (name.len() / 3).max(1);
+  candidates.into_iter()
+    .map(|c| (edit_distance(name, c), c))
+    .filter(|(d, _)| *d <= max)
+    .min()
+    .map(|(_, c)| c)
+}
+
+/// how many characters have to be inserted, deleted or changed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut prev = row[0];
+    row[0] = i + 1;
+    for j in 0..b.len() {
+      let cost = if ca == b[j] { prev } else { prev + 1 };
+      prev = row[j + 1];
+      row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+    }
+  }
+  row[b.len()]
+}
+
+/// reference documentation for the standard library, as markdown.
+pub fn reference_docs() -> String {
+  let mut out = String::from("# Standard library\n\n");
+  out.push_str("These wrap RARS system calls, and are declared in `src/stdlib.rs` and\n");
+  out.push_str("implemented in `resources/stdlib.s`. This file is generated by\n");
+  out.push_str("`compiler --stdlib-docs`.\n");
+  for sig in SIGNATURES {
+    let params: Vec<String> = sig.params.iter().map(|(p, k)| format!("{}: {}", p, k.name())).collect();
+    let ret = match sig.ret {
+      Kind::Void => String::new(),
+      k => format!(" -> {}", k.name()),
+    };
+    out.push_str(&format!("\n### `{}({}){}`\n\n{}", sig.name, params.join(", "), ret, sig.doc));
+    if let Some(n) = sig.ecall {
+      out.push_str(&format!(" (ecall {})", n));
+    }
+    out.push('\n');
+  }
+  out
+}
+
+pub fn get(name: &str) -> Option<Routine> {
+  routines().into_iter().find(|r| r.name == name)
+}
+
+/// the ecall number if `code` is nothing but a label, `li a7, N`, `ecall` and `ret`.
+fn ecall_number(code: &str) -> Option<i64> {
+  let instrs: Vec<String> = code.lines()
+    .map(|l| l.split('#').next().unwrap().trim())
+    .filter(|l| !l.is_empty() && !l.ends_with(':'))
+    .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+    .collect();
+  match instrs.as_slice() {
+    [li, ecall, ret] if ecall == "ecall" && ret == "ret" => li.strip_prefix("li a7, ")?.parse().ok(),
+    _ => None,
+  }
+}
+
+/**
+ * The assembly for every routine in `called`, plus whatever those need in
+ * turn. Names that aren't in the standard library (user functions) are
+ * ignored.
+ */
+pub fn link<'a>(called: impl IntoIterator<Item = &'a str>) -> String {
+  let all = routines();
+  let mut wanted: HashSet<&str> = HashSet::new();
+  let mut stack: Vec<&str> = called.into_iter().collect();
+  while let Some(name) = stack.pop() {
+    if let Some(r) = all.iter().find(|r| r.name == name) {
+      if wanted.insert(r.name) {
+        stack.extend(r.needs.iter().copied());
+      }
+    }
+  }
+  all.iter()
+    .filter(|r| wanted.contains(r.name))
+    .map(|r| r.code.as_str())
+    .collect()
+}